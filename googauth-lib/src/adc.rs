@@ -0,0 +1,135 @@
+use std::env;
+use std::fs;
+use std::fs::create_dir_all;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use dirs::home_dir;
+use serde::{Deserialize, Serialize};
+
+use crate::config_file::{read_json, write_file_atomically, ConfigFile, DEFAULT_REDIRECT_URL};
+use crate::credential_store::{lock_profile, CredentialStore};
+use crate::errors::LibError;
+
+/// The name of the Application Default Credentials file in the gcloud configuration directory.
+pub const ADC_FILE_NAME: &str = "application_default_credentials.json";
+
+/// The scopes that `gcloud auth application-default login` asks for by default.
+pub const ADC_SCOPES: [&str; 3] = [
+    "openid",
+    "https://www.googleapis.com/auth/userinfo.email",
+    "https://www.googleapis.com/auth/cloud-platform",
+];
+
+const AUTHORIZED_USER: &str = "authorized_user";
+
+/// Application Default Credentials of a user, which Google client libraries refresh themselves.
+#[derive(Serialize, Deserialize)]
+struct AuthorizedUser {
+    #[serde(rename = "type")]
+    credentials_type: String,
+    client_id: String,
+    client_secret: String,
+    refresh_token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quota_project_id: Option<String>,
+}
+
+/// Where gcloud and the Google client libraries look for Application Default Credentials:
+/// `$CLOUDSDK_CONFIG/application_default_credentials.json`, by default in `~/.config/gcloud`.
+pub fn default_adc_path() -> Result<PathBuf, LibError> {
+    let gcloud_dir = match env::var_os("CLOUDSDK_CONFIG") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home_dir()
+            .ok_or(LibError::HomeDirectoryNotFound)?
+            .join(".config")
+            .join("gcloud"),
+    };
+    Ok(gcloud_dir.join(ADC_FILE_NAME))
+}
+
+/// The previous version of an Application Default Credentials file, kept by [ConfigFile::export_adc].
+fn adc_backup_file(path: &Path) -> Result<PathBuf, LibError> {
+    let file_name = path.file_name().and_then(|file_name| file_name.to_str()).ok_or(LibError::FilenameError)?;
+    Ok(path.with_file_name(format!(".{}.bak", file_name)))
+}
+
+impl ConfigFile {
+    /// The configuration as `authorized_user` Application Default Credentials.
+    pub fn to_adc_json(&self) -> Result<String, LibError> {
+        let refresh_token = match &self.refresh_token {
+            Some(refresh_token) => refresh_token.clone(),
+            None => return Err(LibError::NoRefreshTokenForConfig(self.name.clone())),
+        };
+        let adc = AuthorizedUser {
+            credentials_type: AUTHORIZED_USER.to_string(),
+            client_id: self.client_id.clone(),
+            client_secret: self.client_secret.clone(),
+            refresh_token,
+            quota_project_id: None,
+        };
+        Ok(serde_json::to_string_pretty(&adc)? + "\n")
+    }
+
+    /// Create a configuration from `authorized_user` Application Default Credentials.
+    /// Its access and ID tokens are fetched with the refresh token when they are first used.
+    pub fn from_adc_json(name: &str, json: &str, scopes: &[String]) -> Result<ConfigFile, LibError> {
+        let invalid = |e: serde_json::Error| LibError::InvalidApplicationDefaultCredentials(e.to_string());
+        let adc: serde_json::Value = serde_json::from_str(json).map_err(invalid)?;
+        // Check the type first, since other types of credentials have other fields.
+        let credentials_type = adc.get("type").and_then(serde_json::Value::as_str).unwrap_or_default();
+        if credentials_type != AUTHORIZED_USER {
+            return Err(LibError::InvalidApplicationDefaultCredentials(format!(
+                "The credentials are of type {:?}, only {} is supported",
+                credentials_type, AUTHORIZED_USER
+            )));
+        }
+        let adc: AuthorizedUser = serde_json::from_value(adc).map_err(invalid)?;
+
+        let mut config = ConfigFile::new(name, &adc.client_id, &adc.client_secret, scopes, DEFAULT_REDIRECT_URL);
+        config.refresh_token = Some(adc.refresh_token);
+        Ok(config)
+    }
+
+    /// Write the configuration as Application Default Credentials to the file, readable only by the user.
+    /// Like [ConfigFile::save_config], the file is replaced through a temporary file, and the previous
+    /// credentials are kept in `.application_default_credentials.json.bak` if they can be read.
+    pub fn export_adc(&self, path: &Path) -> Result<(), LibError> {
+        let json = self.to_adc_json()?;
+        if let Some(dir) = path.parent()
+            && !dir.as_os_str().is_empty()
+        {
+            create_dir_all(dir)?;
+        }
+
+        if read_json(path).is_ok() {
+            fs::copy(path, adc_backup_file(path)?)?;
+        }
+        write_file_atomically(path, json.as_bytes())
+    }
+
+    /// Save a new configuration, with the given name, from an Application Default Credentials file.
+    /// The profile is locked while it is checked that it does not exist yet and saved.
+    pub async fn import_adc<S: CredentialStore + ?Sized>(
+        name: &str,
+        path: &Path,
+        scopes: &[String],
+        store: &S,
+    ) -> Result<ConfigFile, LibError> {
+        let json = match fs::read_to_string(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(LibError::InvalidApplicationDefaultCredentials(format!(
+                    "{} does not exist",
+                    path.display()
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let config = ConfigFile::from_adc_json(name, &json, scopes)?;
+        let _lock = lock_profile(store, name).await?;
+        ConfigFile::ensure_config_does_not_exist(name, store)?;
+        store.save(&config)?;
+        Ok(config)
+    }
+}
@@ -0,0 +1,96 @@
+use serde::Deserialize;
+use url::{Host, Url};
+
+use crate::config_file::ConfigFile;
+use crate::errors::LibError;
+
+/// The `client_secret_*.json` file that the Google Cloud console downloads for an OAuth client.
+#[derive(Deserialize)]
+struct ClientSecretsFile {
+    installed: Option<ClientSecrets>,
+    web: Option<ClientSecrets>,
+}
+
+#[derive(Deserialize)]
+struct ClientSecrets {
+    client_id: String,
+    client_secret: String,
+    auth_uri: String,
+    token_uri: String,
+    #[serde(default)]
+    redirect_uris: Vec<String>,
+}
+
+impl ConfigFile {
+    /// Create a configuration from the contents of a `client_secret_*.json` file, downloaded from
+    /// the Google Cloud console, for either a desktop (`installed`) or a web application (`web`) client.
+    ///
+    /// The redirect URL is the first loopback URL of the client. Desktop clients may redirect to
+    /// any port, so a loopback URL without a port gets port 0, which picks a free port on every login.
+    pub fn from_client_secrets_json(name: &str, json: &str, scopes: &[String]) -> Result<ConfigFile, LibError> {
+        let file: ClientSecretsFile =
+            serde_json::from_str(json).map_err(|e| LibError::InvalidClientSecrets(e.to_string()))?;
+        let (secrets, installed) = match (file.installed, file.web) {
+            (Some(secrets), _) => (secrets, true),
+            (None, Some(secrets)) => (secrets, false),
+            (None, None) => {
+                return Err(LibError::InvalidClientSecrets(
+                    "There is no installed or web client".to_string(),
+                ));
+            }
+        };
+
+        validate_google_uri("auth_uri", &secrets.auth_uri, |host| host == "accounts.google.com")?;
+        validate_google_uri("token_uri", &secrets.token_uri, |host| {
+            host == "accounts.google.com" || host.ends_with(".googleapis.com")
+        })?;
+
+        let mut redirect_url = match secrets.redirect_uris.iter().find_map(|uri| loopback_url(uri)) {
+            Some(redirect_url) => redirect_url,
+            None if installed => Url::parse("http://localhost")?,
+            None => {
+                return Err(LibError::InvalidClientSecrets(
+                    "The client has no loopback redirect URI, such as http://localhost:8080/".to_string(),
+                ));
+            }
+        };
+        if installed && redirect_url.port().is_none() {
+            redirect_url
+                .set_port(Some(0))
+                .map_err(|_| LibError::NoRedirectPortError)?;
+        }
+
+        Ok(ConfigFile::new(
+            name,
+            &secrets.client_id,
+            &secrets.client_secret,
+            scopes,
+            redirect_url.as_str(),
+        ))
+    }
+}
+
+/// Check that the URI is an HTTPS URL of the Google endpoints, since googauth signs in with
+/// the Google issuer rather than with the endpoints in the file.
+fn validate_google_uri(field: &str, uri: &str, is_google_host: impl Fn(&str) -> bool) -> Result<(), LibError> {
+    let url = Url::parse(uri)
+        .map_err(|e| LibError::InvalidClientSecrets(format!("Invalid {} {}: {}", field, uri, e)))?;
+    match url.host_str() {
+        Some(host) if url.scheme() == "https" && is_google_host(host) => Ok(()),
+        _ => Err(LibError::InvalidClientSecrets(format!(
+            "The {} {} is not a Google HTTPS endpoint",
+            field, uri
+        ))),
+    }
+}
+
+/// The URI if it is an HTTP URL on the loopback interface, that the redirect listener can receive.
+fn loopback_url(uri: &str) -> Option<Url> {
+    let url = Url::parse(uri).ok()?;
+    let is_loopback = match url.host()? {
+        Host::Domain(domain) => domain == "localhost",
+        Host::Ipv4(ip) => ip.is_loopback(),
+        Host::Ipv6(ip) => ip.is_loopback(),
+    };
+    (url.scheme() == "http" && is_loopback).then_some(url)
+}
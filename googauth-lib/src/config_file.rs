@@ -1,16 +1,57 @@
 use dirs::home_dir;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::env;
 use std::fs;
-use std::fs::{create_dir_all, set_permissions, File, Permissions};
-use std::io::{BufReader, BufWriter};
-use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::fs::{create_dir_all, set_permissions, File, OpenOptions, Permissions, TryLockError};
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use url::Url;
+use crate::config_toml::{ConfigToml, CONFIG_TOML};
+use crate::credential_store::{lock_profile, CredentialStore, ProfileLock};
+use crate::encryption::{EncryptedConfigFile, FileKind, ENCRYPTED_CONFIG_VERSION, FIRST_ENCRYPTED_CONFIG_VERSION};
 use crate::errors::LibError;
+use crate::profile_name::ProfileName;
+
+/// The version of the configuration files written by this version of googauth.
+///
+/// Version 1 files hold both the settings and the tokens of a profile. Since version 2
+/// the tokens are saved in a separate token cache file.
+pub const CONFIG_VERSION: u32 = 2;
+
+/// The version of the token cache files written by this version of googauth.
+pub const TOKEN_CACHE_VERSION: u32 = 1;
+
+/// The issuer used when a configuration does not specify one.
+pub const GOOGLE_ISSUER_URL: &str = "https://accounts.google.com";
+
+/// The subdirectory of the configuration directory that the tokens are saved in, unless
+/// they are saved elsewhere, see [ConfigBasePath::from].
+pub const TOKEN_CACHE_DIR: &str = "tokens";
+
+/// The redirect URL used when a configuration is created without one.
+pub const DEFAULT_REDIRECT_URL: &str = "http://localhost:8080/";
+
+fn default_issuer_url() -> String {
+    GOOGLE_ISSUER_URL.to_string()
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
 
 /// A configuration file that saves the information necessary
 /// to fetch tokens and to be able to refresh said tokens when
 /// needed.
-#[derive(Serialize, Deserialize)]
+///
+/// It is saved as a profile file with the settings, and a token cache file with the tokens,
+/// see [ConfigFile::save_config]. It serializes to the version 1 format, that had both in one file.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ConfigFile {
     pub version: u32,
     pub name: String,
@@ -18,9 +59,37 @@ pub struct ConfigFile {
     pub client_secret: String,
     pub scopes: Vec<String>,
     pub redirect_url: String,
+    /// The OpenID Connect issuer used for discovery, login and refresh.
+    #[serde(default = "default_issuer_url")]
+    pub issuer_url: String,
+    /// Refresh the tokens when they expire within this many seconds.
+    #[serde(default)]
+    pub min_validity: Option<u64>,
+    /// The service account JSON key file that the tokens are fetched with, instead of a user login.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_account_key: Option<String>,
+    /// The audience of the ID tokens of a service account.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_audience: Option<String>,
+    /// Sign the access tokens of a service account locally, instead of fetching them from the token endpoint.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub self_signed_jwt: bool,
+    /// The audience of self-signed access tokens, e.g. `https://pubsub.googleapis.com/`.
+    /// Without an audience the tokens have the scopes of the configuration.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwt_audience: Option<String>,
+    /// The user that a service account acts as, with domain-wide delegation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
     pub refresh_token: Option<String>,
     pub id_token: Option<Token>,
     pub access_token: Option<Token>,
+    /// The access tokens of a service account acting as other users than the [ConfigFile::subject], by user.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub subject_access_tokens: BTreeMap<String, Token>,
+    /// Whether the configuration is saved encrypted with a passphrase.
+    #[serde(skip)]
+    pub encrypted: bool,
 }
 
 impl ConfigFile {
@@ -32,37 +101,353 @@ impl ConfigFile {
         redirect_url: &str,
     ) -> ConfigFile {
         ConfigFile {
-            version: 1,
+            version: CONFIG_VERSION,
             name: name.to_string(),
             client_id: client_id.to_string(),
             client_secret: client_secret.to_string(),
             scopes: scopes.iter().map(|s| s.to_string()).collect(),
             redirect_url: redirect_url.to_string(),
+            issuer_url: default_issuer_url(),
+            min_validity: None,
+            service_account_key: None,
+            target_audience: None,
+            self_signed_jwt: false,
+            jwt_audience: None,
+            subject: None,
+            refresh_token: None,
+            id_token: None,
+            access_token: None,
+            subject_access_tokens: BTreeMap::new(),
+            encrypted: false,
+        }
+    }
+
+    /// Use a redirect URL with port 0, which lets the OS pick a free port
+    /// for the redirect listener on every login.
+    pub fn set_random_redirect_port(&mut self) -> Result<(), LibError> {
+        let mut redirect_url = Url::parse(&self.redirect_url)?;
+        redirect_url
+            .set_port(Some(0))
+            .map_err(|_| LibError::NoRedirectPortError)?;
+        self.redirect_url = redirect_url.to_string();
+        Ok(())
+    }
+
+    /// How long the tokens must remain valid before they are refreshed, by default.
+    pub fn default_min_validity(&self) -> Duration {
+        Duration::from_secs(self.min_validity.unwrap_or(0))
+    }
+
+    /// Whether the configuration gets ID tokens: user logins always do, and service accounts
+    /// only when they have a [ConfigFile::target_audience].
+    pub fn has_id_tokens(&self) -> bool {
+        self.service_account_key.is_none() || self.target_audience.is_some()
+    }
+}
+
+/// The settings of a profile, saved in the configuration directory. It contains no
+/// tokens, so it can be checked in to version control or shared between machines.
+#[derive(Serialize, Deserialize)]
+struct ProfileFile {
+    version: u32,
+    name: String,
+    client_id: String,
+    client_secret: String,
+    scopes: Vec<String>,
+    redirect_url: String,
+    #[serde(default = "default_issuer_url")]
+    issuer_url: String,
+    #[serde(default)]
+    min_validity: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    service_account_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target_audience: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    self_signed_jwt: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    jwt_audience: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    subject: Option<String>,
+}
+
+/// The tokens of a profile, saved in the token cache directory.
+#[derive(Serialize, Deserialize)]
+struct TokenCacheFile {
+    version: u32,
+    name: String,
+    refresh_token: Option<String>,
+    id_token: Option<Token>,
+    access_token: Option<Token>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    subject_access_tokens: BTreeMap<String, Token>,
+}
+
+impl ConfigFile {
+    fn from_files(profile: ProfileFile, token_cache: Option<TokenCacheFile>, encrypted: bool) -> ConfigFile {
+        let mut config = ConfigFile {
+            version: CONFIG_VERSION,
+            name: profile.name,
+            client_id: profile.client_id,
+            client_secret: profile.client_secret,
+            scopes: profile.scopes,
+            redirect_url: profile.redirect_url,
+            issuer_url: profile.issuer_url,
+            min_validity: profile.min_validity,
+            service_account_key: profile.service_account_key,
+            target_audience: profile.target_audience,
+            self_signed_jwt: profile.self_signed_jwt,
+            jwt_audience: profile.jwt_audience,
+            subject: profile.subject,
             refresh_token: None,
             id_token: None,
             access_token: None,
+            subject_access_tokens: BTreeMap::new(),
+            encrypted,
+        };
+        config.set_tokens(token_cache);
+        config
+    }
+
+    fn set_tokens(&mut self, token_cache: Option<TokenCacheFile>) {
+        if let Some(token_cache) = token_cache {
+            self.refresh_token = token_cache.refresh_token;
+            self.id_token = token_cache.id_token;
+            self.access_token = token_cache.access_token;
+            self.subject_access_tokens = token_cache.subject_access_tokens;
+        }
+    }
+
+    fn to_profile_file(&self) -> ProfileFile {
+        ProfileFile {
+            version: CONFIG_VERSION,
+            name: self.name.clone(),
+            client_id: self.client_id.clone(),
+            client_secret: self.client_secret.clone(),
+            scopes: self.scopes.clone(),
+            redirect_url: self.redirect_url.clone(),
+            issuer_url: self.issuer_url.clone(),
+            min_validity: self.min_validity,
+            service_account_key: self.service_account_key.clone(),
+            target_audience: self.target_audience.clone(),
+            self_signed_jwt: self.self_signed_jwt,
+            jwt_audience: self.jwt_audience.clone(),
+            subject: self.subject.clone(),
+        }
+    }
+
+    fn to_token_cache_file(&self) -> TokenCacheFile {
+        TokenCacheFile {
+            version: TOKEN_CACHE_VERSION,
+            name: self.name.clone(),
+            refresh_token: self.refresh_token.clone(),
+            id_token: self.id_token.clone(),
+            access_token: self.access_token.clone(),
+            subject_access_tokens: self.subject_access_tokens.clone(),
         }
     }
 }
 
+/// The configurations in a configuration directory, see [ConfigFile::scan_configs].
+pub struct ConfigList {
+    /// The configurations that could be read.
+    pub configs: Vec<ConfigFile>,
+    /// The names of the encrypted configurations that could not be decrypted.
+    pub locked: Vec<String>,
+    /// The files that could not be read as a configuration, with the reason,
+    /// e.g. [LibError::ProfileNameMismatch].
+    pub errors: Vec<(String, LibError)>,
+}
+
+/// What [ConfigFile::delete_config] deleted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeletedConfig {
+    /// The profile file, and its tokens.
+    Profile,
+    /// Only the tokens, since the profile is defined in `config.toml` and has to be removed from there.
+    Tokens,
+}
+
+/// Asked for the passphrase of an encrypted configuration, given the name of the configuration.
+pub type PassphraseProvider = Arc<dyn Fn(&str) -> Result<String, LibError> + Send + Sync>;
+
 pub struct ConfigBasePath {
     path: PathBuf,
+    token_cache_path: PathBuf,
+    passphrase_provider: Option<PassphraseProvider>,
+    passphrases: Mutex<Passphrases>,
+    config_toml: Mutex<Option<CachedConfigToml>>,
+}
+
+/// The environment variables and the home directory that [ConfigBasePath::resolve] uses.
+/// Empty environment variables are `None`.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigEnvironment {
+    pub googauth_home: Option<PathBuf>,
+    pub xdg_config_home: Option<PathBuf>,
+    pub xdg_state_home: Option<PathBuf>,
+    pub home: Option<PathBuf>,
+}
+
+impl ConfigEnvironment {
+    /// The environment of this process.
+    pub fn current() -> ConfigEnvironment {
+        ConfigEnvironment {
+            googauth_home: env_dir("GOOGAUTH_HOME"),
+            xdg_config_home: env_dir("XDG_CONFIG_HOME"),
+            xdg_state_home: env_dir("XDG_STATE_HOME"),
+            home: home_dir(),
+        }
+    }
+}
+
+/// The parsed `config.toml`, with its modification time, or `None` if there is no `config.toml`.
+struct CachedConfigToml {
+    modified: Option<SystemTime>,
+    config_toml: Arc<ConfigToml>,
+}
+
+/// The passphrases that have decrypted a configuration, so that the provider is only asked again
+/// for a configuration that none of them decrypts.
+#[derive(Default)]
+struct Passphrases {
+    by_name: BTreeMap<String, String>,
+    last: Option<String>,
 }
 
 impl ConfigBasePath {
+    /// The directories that profiles and tokens are saved in, in order of preference:
+    ///
+    /// 1. `$GOOGAUTH_HOME`, with the tokens in its `tokens` subdirectory.
+    /// 2. `~/.googauth`, if it already exists, with the tokens in the XDG state directory.
+    /// 3. `$XDG_CONFIG_HOME/googauth`, with the tokens in `$XDG_STATE_HOME/googauth`.
+    ///    These default to `~/.config/googauth` and `~/.local/state/googauth`.
+    #[allow(clippy::should_implement_trait)]
     pub fn default() -> Result<ConfigBasePath, LibError> {
-        let mut config_dir = match home_dir() {
+        ConfigBasePath::resolve(None, &ConfigEnvironment::current())
+    }
+
+    /// The directories that profiles and tokens are saved in: `config_dir` if it is given, e.g. with
+    /// a command line option, with the tokens in its `tokens` subdirectory, or else those of
+    /// [ConfigBasePath::default] in the environment.
+    pub fn resolve(config_dir: Option<PathBuf>, environment: &ConfigEnvironment) -> Result<ConfigBasePath, LibError> {
+        if let Some(config_dir) = config_dir.or_else(|| environment.googauth_home.clone()) {
+            return Ok(ConfigBasePath::from(config_dir));
+        }
+
+        let home = match &environment.home {
             None => {
                 return Err(LibError::HomeDirectoryNotFound);
             }
             Some(dir) => dir,
         };
-        config_dir.push(".googauth");
-        Ok(ConfigBasePath { path: config_dir } )
+        let mut state_dir = environment.xdg_state_home.clone().unwrap_or_else(|| home.join(".local").join("state"));
+        state_dir.push("googauth");
+
+        let legacy_dir = home.join(".googauth");
+        let config_dir = if legacy_dir.is_dir() {
+            legacy_dir
+        } else {
+            let mut config_dir = environment.xdg_config_home.clone().unwrap_or_else(|| home.join(".config"));
+            config_dir.push("googauth");
+            config_dir
+        };
+        Ok(ConfigBasePath::from(config_dir).with_token_cache_path(state_dir))
     }
 
+    /// Save the profiles in the given directory, and the tokens in its `tokens` subdirectory.
     pub fn from(path: PathBuf) -> ConfigBasePath {
-        ConfigBasePath { path }
+        let mut token_cache_path = path.clone();
+        token_cache_path.push(TOKEN_CACHE_DIR);
+        ConfigBasePath { path, token_cache_path, passphrase_provider: None, passphrases: Mutex::default(), config_toml: Mutex::default() }
+    }
+
+    /// Save the tokens in the given directory.
+    pub fn with_token_cache_path(mut self, token_cache_path: PathBuf) -> ConfigBasePath {
+        self.token_cache_path = token_cache_path;
+        self
+    }
+
+    /// Use the provider to get the passphrase when reading or saving encrypted configurations.
+    pub fn with_passphrase_provider(mut self, passphrase_provider: PassphraseProvider) -> ConfigBasePath {
+        self.passphrase_provider = Some(passphrase_provider);
+        self
+    }
+
+    /// The `config.toml` in the directory, which is only parsed again when it has been modified.
+    fn config_toml(&self) -> Result<Arc<ConfigToml>, LibError> {
+        let modified = match fs::metadata(self.path.join(CONFIG_TOML)) {
+            Ok(metadata) => Some(metadata.modified()?),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut cached = self.config_toml.lock().unwrap();
+        if let Some(cached) = cached.as_ref()
+            && cached.modified == modified
+        {
+            return Ok(cached.config_toml.clone());
+        }
+        let config_toml = Arc::new(ConfigToml::read(&self.path)?);
+        *cached = Some(CachedConfigToml { modified, config_toml: config_toml.clone() });
+        Ok(config_toml)
+    }
+
+    /// The passphrase to encrypt a configuration with: the one that decrypted it, or else the one
+    /// that decrypted a configuration last, e.g. when it is copied, or else the one of the provider.
+    fn passphrase(&self, name: &str) -> Result<String, LibError> {
+        let known = {
+            let passphrases = self.passphrases.lock().unwrap();
+            passphrases.by_name.get(name).or(passphrases.last.as_ref()).cloned()
+        };
+        match known {
+            Some(passphrase) => Ok(passphrase),
+            None => self.ask_for_passphrase(name),
+        }
+    }
+
+    fn ask_for_passphrase(&self, name: &str) -> Result<String, LibError> {
+        match &self.passphrase_provider {
+            Some(passphrase_provider) => match passphrase_provider(name)? {
+                passphrase if passphrase.is_empty() => Err(LibError::PassphraseRequired(name.to_string())),
+                passphrase => Ok(passphrase),
+            },
+            None => Err(LibError::PassphraseRequired(name.to_string())),
+        }
+    }
+
+    /// Decrypt a file, trying the passphrases that decrypted other files before asking the provider,
+    /// so that profiles that share a passphrase only ask for it once. A passphrase is only
+    /// remembered once it has decrypted a file.
+    fn decrypt(&self, encrypted_file: &EncryptedConfigFile, kind: FileKind) -> Result<Value, LibError> {
+        let name = &encrypted_file.name;
+        let mut known: Vec<String> = Vec::new();
+        {
+            let passphrases = self.passphrases.lock().unwrap();
+            for passphrase in passphrases.by_name.get(name).into_iter().chain(passphrases.by_name.values()) {
+                if !known.contains(passphrase) {
+                    known.push(passphrase.clone());
+                }
+            }
+        }
+
+        for passphrase in known {
+            if let Ok(contents) = encrypted_file.decrypt(kind, &passphrase) {
+                self.remember_passphrase(name, passphrase);
+                return Ok(contents);
+            }
+        }
+
+        let passphrase = self.ask_for_passphrase(name)?;
+        let contents = encrypted_file.decrypt(kind, &passphrase)?;
+        self.remember_passphrase(name, passphrase);
+        Ok(contents)
+    }
+
+    fn remember_passphrase(&self, name: &str, passphrase: String) {
+        let mut passphrases = self.passphrases.lock().unwrap();
+        passphrases.by_name.insert(name.to_string(), passphrase.clone());
+        passphrases.last = Some(passphrase);
     }
 }
 
@@ -76,65 +461,571 @@ impl Token {
     pub fn new(secret: String, exp: u64) -> Token {
         Token { secret, exp }
     }
+
+    /// Whether the expiry time of the token is known. It is not known
+    /// if the provider did not say when the token expires.
+    pub fn has_expiry(&self) -> bool {
+        self.exp != 0
+    }
+
+    /// Whether the token expires within `min_validity` from `now` (in seconds since the epoch).
+    /// A token without a known expiry time never expires.
+    pub fn expires_within(&self, min_validity: Duration, now: u64) -> bool {
+        self.has_expiry() && self.exp < now.saturating_add(min_validity.as_secs())
+    }
 }
 
 impl ConfigFile {
-    pub fn config_file(name: &str, config_base_path: &ConfigBasePath) -> Result<PathBuf, LibError> {
-        let mut config_dir = config_base_path.path.clone();
-        config_dir.push(name);
-        Ok(config_dir)
+    pub fn config_file(name: &ProfileName, config_base_path: &ConfigBasePath) -> PathBuf {
+        config_base_path.path.join(name)
+    }
+
+    /// The previous version of a configuration, kept by [ConfigFile::save_config].
+    pub fn backup_config_file(name: &ProfileName, config_base_path: &ConfigBasePath) -> PathBuf {
+        backup_file(&config_base_path.path, name)
+    }
+
+    /// The file that the tokens of a configuration are saved in.
+    pub fn token_cache_file(name: &ProfileName, config_base_path: &ConfigBasePath) -> PathBuf {
+        config_base_path.token_cache_path.join(name)
     }
 
+    /// List the configurations that can be read, skipping other files, followed by those that
+    /// are only defined in `config.toml`. Configurations that can not be read, e.g. encrypted
+    /// configurations that can not be decrypted, are skipped, see [ConfigFile::scan_configs]
+    /// to list those as well.
     pub fn list_configs(config_base_path: &ConfigBasePath) -> Result<Vec<ConfigFile>, LibError> {
+        Ok(ConfigFile::scan_configs(config_base_path)?.configs)
+    }
+
+    /// Like [ConfigFile::list_configs], but also lists the encrypted configurations that can not
+    /// be decrypted, because no passphrase or a wrong one was given, the files that have
+    /// a profile with another name, and the profiles in `config.toml` that can not be read.
+    pub fn scan_configs(config_base_path: &ConfigBasePath) -> Result<ConfigList, LibError> {
         let config_dir = config_base_path.path.clone();
+        let mut result = ConfigList { configs: Vec::new(), locked: Vec::new(), errors: Vec::new() };
 
         if config_dir.is_dir() {
-            let mut result = Vec::new();
+            let config_toml = config_base_path.config_toml()?;
+            let mut names: Vec<String> = Vec::new();
             let dirs = fs::read_dir(config_dir)?;
             for entry in dirs {
                 let entry = entry?;
                 let path = entry.path();
                 if path.is_file() {
                     let file_name = path.file_name().ok_or(LibError::FilenameError)?.to_str().ok_or(LibError::FilenameError)?;
-                    if let Ok(config_file) = ConfigFile::read_config(file_name, config_base_path) {
-                        result.push(config_file);
+                    // Skip lock, backup and temporary files.
+                    if file_name.starts_with('.') || file_name == CONFIG_TOML {
+                        continue;
                     }
+                    names.push(file_name.to_string());
+                }
+            }
+            // Other files in the directory may not be profiles, but those in config.toml are.
+            let file_count = names.len();
+            for name in config_toml.profile_names() {
+                if !names.iter().any(|file_name| file_name == name) {
+                    names.push(name.to_string());
+                }
+            }
+
+            for (index, name) in names.into_iter().enumerate() {
+                match ProfileName::new(&name).and_then(|profile_name| ConfigFile::read_config(&profile_name, config_base_path)) {
+                    Ok(config_file) => result.configs.push(config_file),
+                    Err(LibError::PassphraseRequired(_) | LibError::DecryptionFailed(_)) => result.locked.push(name),
+                    Err(e @ LibError::ProfileNameMismatch(_, _)) => result.errors.push((name, e)),
+                    Err(e) if index >= file_count => result.errors.push((name, e)),
+                    Err(_) => (),
                 }
             }
             return Ok(result);
         }
+        // A directory that has not been created yet has no configurations.
+        if !config_dir.exists() {
+            return Ok(result);
+        }
         Err(LibError::ConfigsDirectoryNotADirectory(config_dir))
     }
 
-    pub fn read_config(name: &str, config_base_path: &ConfigBasePath) -> Result<ConfigFile, LibError> {
-        let config_dir = ConfigFile::config_file(name, config_base_path)?;
+    /// Read the configuration with the given name, merging the profile file with the token cache.
+    /// If a file can not be parsed, e.g. after a crash, its previous version is read instead.
+    ///
+    /// Files saved by older versions of googauth are migrated to the current versions, and saved again.
+    /// Files saved by newer versions fail with [LibError::UnsupportedConfigVersion].
+    ///
+    /// If there is no profile file, the profile is read from `config.toml`, see [CONFIG_TOML].
+    pub fn read_config(name: &ProfileName, config_base_path: &ConfigBasePath) -> Result<ConfigFile, LibError> {
+        let (config, migrated) = ConfigFile::read_config_files(name, config_base_path)?;
+        if !migrated {
+            return Ok(config);
+        }
+
+        // Save the migrated files under the lock, after reading them again, so that the tokens of
+        // a concurrent refresh are not overwritten. The lock is not waited for, as the caller may
+        // hold it already. The files are then migrated by a later read.
+        let _lock = match ConfigFile::try_lock(name, config_base_path)? {
+            Some(lock) => lock,
+            None => return Ok(config),
+        };
+        let (config, migrated) = ConfigFile::read_config_files(name, config_base_path)?;
+        if migrated {
+            config.save_config(config_base_path)?;
+
+            // The backup is the file in the old format, which may have tokens that are now in the token cache.
+            match fs::remove_file(backup_file(&config_base_path.path, name)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
 
-        let config_file = File::open(config_dir.as_path())?;
-        let config_file_reader = BufReader::new(config_file);
-        let config = serde_json::from_reader(config_file_reader)?;
         Ok(config)
     }
 
-    pub fn save_config(&self, config_base_path: &ConfigBasePath) -> Result<(), LibError> {
-        let mut config_dir = config_base_path.path.clone();
+    /// Read and merge the files of a configuration, migrating them in memory.
+    /// Returns whether they have to be saved again.
+    fn read_config_files(name: &ProfileName, config_base_path: &ConfigBasePath) -> Result<(ConfigFile, bool), LibError> {
+        let (mut profile, encryption) = match read_file(&config_base_path.path, name, FileKind::Profile, config_base_path) {
+            Err(LibError::IoError(e)) if e.kind() == ErrorKind::NotFound => {
+                return ConfigFile::read_config_toml_profile(name, config_base_path);
+            }
+            result => result?,
+        };
+        // Fail before asking for the passphrase of a token cache that can not be used anyway.
+        file_version(name, &profile, CONFIG_VERSION)?;
 
-        create_dir_all(config_dir.as_path())?;
-        if cfg!(unix) {
-            set_permissions(config_dir.as_path(), Permissions::from_mode(0o700))?
+        let (mut token_cache, token_cache_encryption) =
+            match read_file(&config_base_path.token_cache_path, name, FileKind::TokenCache, config_base_path) {
+                Ok((token_cache, encryption)) => (Some(token_cache), encryption),
+                Err(LibError::IoError(e)) if e.kind() == ErrorKind::NotFound => (None, None),
+                Err(e) => return Err(e),
+            };
+
+        let migrated = migrate(name, &mut profile, &mut token_cache)?
+            || is_outdated(encryption)
+            || is_outdated(token_cache_encryption);
+        let token_cache = token_cache.map(serde_json::from_value).transpose()?;
+        let config = ConfigFile::from_files(serde_json::from_value(profile)?, token_cache, encryption.is_some());
+        if config.name != name.as_str() {
+            return Err(LibError::ProfileNameMismatch(name.to_string(), config.name));
         }
 
-        config_dir.push(self.name.to_string());
+        Ok((config, migrated))
+    }
+
+    /// Read a profile that is only defined in `config.toml`, with its tokens from the token cache.
+    fn read_config_toml_profile(name: &ProfileName, config_base_path: &ConfigBasePath) -> Result<(ConfigFile, bool), LibError> {
+        let mut config = match config_base_path.config_toml()?.resolve(name)? {
+            Some(config) => config,
+            None => return Err(LibError::NoSuchConfiguration(name.to_string())),
+        };
+
+        let (mut token_cache, encryption) =
+            match read_file(&config_base_path.token_cache_path, name, FileKind::TokenCache, config_base_path) {
+                Ok(token_cache) => token_cache,
+                Err(LibError::IoError(e)) if e.kind() == ErrorKind::NotFound => return Ok((config, false)),
+                Err(e) => return Err(e),
+            };
+        let migrated = migrate_token_cache(name, &mut token_cache)? || is_outdated(encryption);
+        config.set_tokens(Some(serde_json::from_value(token_cache)?));
+        config.encrypted = encryption.is_some();
+
+        Ok((config, migrated))
+    }
+
+    /// Whether the settings of the configuration are only defined in `config.toml`, so that only its
+    /// tokens are saved, see [CONFIG_TOML].
+    pub fn is_defined_in_config_toml(name: &ProfileName, config_base_path: &ConfigBasePath) -> Result<bool, LibError> {
+        Ok(!config_base_path.path.join(name).exists() && config_base_path.config_toml()?.defines(name))
+    }
+
+    /// Delete the saved configuration with the given name, its tokens, their previous versions,
+    /// and its lock file. A profile that is only defined in `config.toml` has to be removed from
+    /// there, so only its tokens are deleted, see [DeletedConfig::Tokens].
+    pub fn delete_config(name: &ProfileName, config_base_path: &ConfigBasePath) -> Result<DeletedConfig, LibError> {
+        remove_file(&config_base_path.token_cache_path, name)?;
+        match fs::remove_file(lock_file(&config_base_path.token_cache_path, name)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+        if remove_file(&config_base_path.path, name)? {
+            Ok(DeletedConfig::Profile)
+        } else if config_base_path.config_toml()?.defines(name) {
+            Ok(DeletedConfig::Tokens)
+        } else {
+            Err(LibError::NoSuchConfiguration(name.to_string()))
+        }
+    }
+
+    /// Rename a saved configuration, keeping its tokens. The lock file of the old name is deleted.
+    pub fn rename_config<S: CredentialStore + ?Sized>(
+        name: &ProfileName,
+        new_name: &ProfileName,
+        store: &S,
+    ) -> Result<ConfigFile, LibError> {
+        let mut config = store.load(name)?;
+        ConfigFile::ensure_config_does_not_exist(new_name, store)?;
+
+        config.name = new_name.to_string();
+        store.save(&config)?;
+        store.delete(name)?;
+
+        Ok(config)
+    }
+
+    /// Save a new configuration with the client settings of an existing one,
+    /// but without any tokens.
+    pub fn copy_config<S: CredentialStore + ?Sized>(
+        name: &ProfileName,
+        new_name: &ProfileName,
+        store: &S,
+    ) -> Result<ConfigFile, LibError> {
+        let config = store.load(name)?;
+        ConfigFile::ensure_config_does_not_exist(new_name, store)?;
+
+        let mut new_config = ConfigFile::new(
+            new_name,
+            &config.client_id,
+            &config.client_secret,
+            &config.scopes,
+            &config.redirect_url,
+        );
+        new_config.issuer_url = config.issuer_url;
+        new_config.min_validity = config.min_validity;
+        new_config.service_account_key = config.service_account_key;
+        new_config.target_audience = config.target_audience;
+        new_config.self_signed_jwt = config.self_signed_jwt;
+        new_config.jwt_audience = config.jwt_audience;
+        new_config.subject = config.subject;
+        new_config.encrypted = config.encrypted;
+        store.save(&new_config)?;
+
+        Ok(new_config)
+    }
+
+    pub(crate) fn ensure_config_does_not_exist<S: CredentialStore + ?Sized>(
+        name: &str,
+        store: &S,
+    ) -> Result<(), LibError> {
+        match store.load(name) {
+            Err(LibError::NoSuchConfiguration(_)) => Ok(()),
+            Err(e) => Err(e),
+            Ok(_) => Err(LibError::ConfigurationAlreadyExists(name.to_string())),
+        }
+    }
+
+    /// Take the advisory lock of the configuration with the given name,
+    /// waiting for any other process that holds it, see [lock_profile].
+    pub async fn lock(name: &ProfileName, config_base_path: &ConfigBasePath) -> Result<ProfileLock, LibError> {
+        lock_profile(config_base_path, name).await
+    }
 
-        let config_file = File::create(config_dir.as_path())?;
+    /// Try to take the advisory lock of the configuration with the given name, a lock file in
+    /// the token cache directory. Returns `None` if another process holds the lock.
+    ///
+    /// Hold it while reading, refreshing and saving the tokens of a configuration,
+    /// so that concurrent processes do not refresh the same tokens at once.
+    pub fn try_lock(name: &ProfileName, config_base_path: &ConfigBasePath) -> Result<Option<ProfileLock>, LibError> {
+        let lock_dir = &config_base_path.token_cache_path;
 
+        create_dir_all(lock_dir)?;
         if cfg!(unix) {
-            set_permissions(config_dir.as_path(), Permissions::from_mode(0o600))?;
+            set_permissions(lock_dir, Permissions::from_mode(0o700))?
         }
 
-        let config_file_writer = BufWriter::new(config_file);
+        let lock_file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .mode(0o600)
+            .open(lock_file(lock_dir, name))?;
 
-        serde_json::to_writer(config_file_writer, self)?;
+        match lock_file.try_lock() {
+            Ok(()) => Ok(Some(Box::new(lock_file))),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
 
-        Ok(())
+    /// Whether any of the tokens of this configuration expire later than
+    /// those of `other`, e.g. because another process has refreshed them.
+    pub(crate) fn has_newer_tokens_than(&self, other: &ConfigFile) -> bool {
+        let exp = |token: &Option<Token>| token.as_ref().map(|token| token.exp).unwrap_or(0);
+        exp(&self.access_token) > exp(&other.access_token) || exp(&self.id_token) > exp(&other.id_token)
+    }
+
+    /// Save the configuration without risking to corrupt it on a crash or a full disk.
+    ///
+    /// The settings are saved in the profile file, and the tokens in the token cache file.
+    /// Each file is written to a temporary file that is synced and then renamed over
+    /// the file. The previous version is kept as a backup, that [ConfigFile::read_config]
+    /// falls back to. A plaintext profile file that has not changed is not written, and neither is
+    /// the profile file of a profile that is only defined in `config.toml`.
+    pub fn save_config(&self, config_base_path: &ConfigBasePath) -> Result<(), LibError> {
+        let name = ProfileName::new(&self.name)?;
+        write_file(
+            &config_base_path.token_cache_path,
+            &name,
+            FileKind::TokenCache,
+            &serde_json::to_value(self.to_token_cache_file())?,
+            self.encrypted,
+            config_base_path,
+        )?;
+
+        if ConfigFile::is_defined_in_config_toml(&name, config_base_path)? {
+            return Ok(());
+        }
+
+        let profile_path = config_base_path.path.join(&name);
+
+        let profile = serde_json::to_value(self.to_profile_file())?;
+        if !self.encrypted
+            && read_plaintext_file(&profile_path).as_ref() == Some(&profile)
+        {
+            return Ok(());
+        }
+
+        write_file(&config_base_path.path, &name, FileKind::Profile, &profile, self.encrypted, config_base_path)
+    }
+}
+
+/// Upgrades a profile file from one version to the next. The token cache is given as well,
+/// for changes that move values between the two files.
+type ProfileMigration = fn(&mut Value, &mut Option<Value>);
+
+/// Upgrades a token cache file from one version to the next.
+type TokenCacheMigration = fn(&mut Value);
+
+/// `PROFILE_MIGRATIONS[n]` upgrades a profile file from version `n + 1` to version `n + 2`.
+const PROFILE_MIGRATIONS: [ProfileMigration; CONFIG_VERSION as usize - 1] = [migrate_profile_v1];
+
+/// `TOKEN_CACHE_MIGRATIONS[n]` upgrades a token cache file from version `n + 1` to version `n + 2`.
+const TOKEN_CACHE_MIGRATIONS: [TokenCacheMigration; TOKEN_CACHE_VERSION as usize - 1] = [];
+
+/// Upgrade the profile file and the token cache file, one version at a time, to the
+/// versions written by this version of googauth. Returns whether anything was upgraded.
+fn migrate(name: &str, profile: &mut Value, token_cache: &mut Option<Value>) -> Result<bool, LibError> {
+    let mut migrated = false;
+
+    for version in file_version(name, profile, CONFIG_VERSION)?..CONFIG_VERSION {
+        PROFILE_MIGRATIONS[version as usize - 1](profile, token_cache);
+        profile["version"] = Value::from(version + 1);
+        migrated = true;
+    }
+
+    if let Some(token_cache) = token_cache {
+        migrated |= migrate_token_cache(name, token_cache)?;
+    }
+
+    Ok(migrated)
+}
+
+/// Upgrade the token cache file, one version at a time. Returns whether it was upgraded.
+fn migrate_token_cache(name: &str, token_cache: &mut Value) -> Result<bool, LibError> {
+    let mut migrated = false;
+    for version in file_version(name, token_cache, TOKEN_CACHE_VERSION)?..TOKEN_CACHE_VERSION {
+        TOKEN_CACHE_MIGRATIONS[version as usize - 1](token_cache);
+        token_cache["version"] = Value::from(version + 1);
+        migrated = true;
+    }
+    Ok(migrated)
+}
+
+/// The version of a file, if it is one that this version of googauth can read.
+fn file_version(name: &str, contents: &Value, current_version: u32) -> Result<u32, LibError> {
+    match contents.get("version").and_then(Value::as_u64) {
+        Some(version) if (1..=current_version as u64).contains(&version) => Ok(version as u32),
+        version => Err(LibError::UnsupportedConfigVersion(name.to_string(), version.unwrap_or(0))),
+    }
+}
+
+/// Version 2 moved the tokens from the profile file to a version 1 token cache file.
+fn migrate_profile_v1(profile: &mut Value, token_cache: &mut Option<Value>) {
+    let mut tokens = serde_json::Map::new();
+    tokens.insert("version".to_string(), Value::from(1));
+    tokens.insert("name".to_string(), profile["name"].clone());
+    if let Some(profile) = profile.as_object_mut() {
+        for key in ["refresh_token", "id_token", "access_token"] {
+            if let Some(token) = profile.remove(key) {
+                tokens.insert(key.to_string(), token);
+            }
+        }
+    }
+    *token_cache = Some(Value::Object(tokens));
+}
+
+/// A directory from an environment variable, ignoring it when it is unset or empty.
+fn env_dir(name: &str) -> Option<PathBuf> {
+    match env::var_os(name) {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => None,
+    }
+}
+
+fn backup_file(dir: &Path, name: &ProfileName) -> PathBuf {
+    let mut backup_file = dir.to_path_buf();
+    backup_file.push(format!(".{}.bak", name));
+    backup_file
+}
+
+/// The file that is locked by [ConfigFile::try_lock].
+fn lock_file(dir: &Path, name: &ProfileName) -> PathBuf {
+    dir.join(format!(".{}.lock", name))
+}
+
+/// Read the file with the given name in the directory, decrypting it if it is encrypted.
+/// Falls back to the backup if the file can not be parsed. Returns the contents, and the
+/// version of the encryption if the file is encrypted.
+fn read_file(
+    dir: &Path,
+    name: &ProfileName,
+    kind: FileKind,
+    config_base_path: &ConfigBasePath,
+) -> Result<(Value, Option<u32>), LibError> {
+    let mut path = dir.to_path_buf();
+    path.push(name);
+
+    let contents = match read_json(&path) {
+        Err(LibError::JsonError(e)) => {
+            read_json(&backup_file(dir, name)).map_err(|_| LibError::JsonError(e))?
+        }
+        result => result?,
+    };
+
+    if is_encrypted(&contents) {
+        let encrypted_file: EncryptedConfigFile = serde_json::from_value(contents)?;
+        return Ok((config_base_path.decrypt(&encrypted_file, kind)?, Some(encrypted_file.version)));
+    }
+
+    Ok((contents, None))
+}
+
+/// Whether a file was encrypted by an older version, see [read_file].
+fn is_outdated(encryption: Option<u32>) -> bool {
+    encryption.is_some_and(|version| version < ENCRYPTED_CONFIG_VERSION)
+}
+
+pub(crate) fn read_json(path: &Path) -> Result<Value, LibError> {
+    let file = File::open(path)?;
+    Ok(serde_json::from_reader(BufReader::new(file))?)
+}
+
+/// The contents of a file if it can be parsed and is not encrypted.
+fn read_plaintext_file(path: &Path) -> Option<Value> {
+    read_json(path).ok().filter(|contents| !is_encrypted(contents))
+}
+
+fn is_encrypted(contents: &Value) -> bool {
+    contents
+        .get("version")
+        .and_then(Value::as_u64)
+        .is_some_and(|version| version >= FIRST_ENCRYPTED_CONFIG_VERSION as u64)
+}
+
+/// Write the file with the given name in the directory, encrypting it if `encrypted` is set.
+fn write_file(
+    dir: &Path,
+    name: &ProfileName,
+    kind: FileKind,
+    contents: &Value,
+    encrypted: bool,
+    config_base_path: &ConfigBasePath,
+) -> Result<(), LibError> {
+    create_dir_all(dir)?;
+    if cfg!(unix) {
+        set_permissions(dir, Permissions::from_mode(0o700))?
+    }
+
+    let mut path = dir.to_path_buf();
+    path.push(name);
+
+    let contents = if encrypted {
+        let passphrase = config_base_path.passphrase(name)?;
+        serde_json::to_value(EncryptedConfigFile::encrypt(name, kind, contents, &passphrase)?)?
+    } else {
+        contents.clone()
+    };
+
+    // Only back up a previous version that can be read, so that a corrupt
+    // file never replaces a good backup. Never keep a plaintext backup of
+    // an encrypted file.
+    let backup_file = backup_file(dir, name);
+    match read_json(&path) {
+        Ok(previous) if encrypted && !is_encrypted(&previous) => {
+            match fs::remove_file(backup_file.as_path()) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+        Ok(_) => {
+            fs::copy(path.as_path(), backup_file.as_path())?;
+        }
+        Err(_) => (),
+    }
+
+    write_file_atomically(&path, &serde_json::to_vec(&contents)?)
+}
+
+/// Replace the file with the contents without risking to corrupt it on a crash or a full disk.
+/// The contents are written to a temporary file, readable only by the user, that is synced and
+/// then renamed over the file.
+pub(crate) fn write_file_atomically(path: &Path, contents: &[u8]) -> Result<(), LibError> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file_name = path.file_name().and_then(|file_name| file_name.to_str()).ok_or(LibError::FilenameError)?;
+    let mut temp_file = dir.to_path_buf();
+    temp_file.push(format!(".{}.{}.tmp", file_name, process::id()));
+
+    if let Err(e) = write_new_file(&temp_file, contents) {
+        let _ = fs::remove_file(temp_file.as_path());
+        return Err(e);
+    }
+
+    if let Err(e) = fs::rename(temp_file.as_path(), path) {
+        let _ = fs::remove_file(temp_file.as_path());
+        return Err(e.into());
+    }
+
+    // Persist the rename.
+    File::open(dir)?.sync_all()?;
+
+    Ok(())
+}
+
+fn write_new_file(path: &Path, contents: &[u8]) -> Result<(), LibError> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .mode(0o600)
+        .open(path)?;
+
+    if cfg!(unix) {
+        set_permissions(path, Permissions::from_mode(0o600))?;
+    }
+
+    let mut writer = BufWriter::new(file);
+    writer.write_all(contents)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+
+    Ok(())
+}
+
+/// Remove the file with the given name in the directory, and its backup.
+/// Returns whether the file existed.
+fn remove_file(dir: &Path, name: &ProfileName) -> Result<bool, LibError> {
+    match fs::remove_file(backup_file(dir, name)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => (),
+    }
+
+    let mut path = dir.to_path_buf();
+    path.push(name);
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
     }
 }
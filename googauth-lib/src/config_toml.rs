@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::config_file::{ConfigFile, DEFAULT_REDIRECT_URL, GOOGLE_ISSUER_URL};
+use crate::errors::LibError;
+
+/// The name of the file, in the configuration directory, that defines shared client registrations
+/// and the profiles that use them.
+pub const CONFIG_TOML: &str = "config.toml";
+
+/// A hand-written `config.toml`, e.g.
+///
+/// ```toml
+/// [defaults]
+/// scopes = ["openid", "email"]
+///
+/// [clients.corp]
+/// client_id = "1234.apps.googleusercontent.com"
+/// client_secret = "secret"
+///
+/// [profiles.work]
+/// client = "corp"
+/// ```
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ConfigToml {
+    #[serde(default)]
+    defaults: Defaults,
+    #[serde(default)]
+    clients: HashMap<String, ClientRegistration>,
+    #[serde(default)]
+    profiles: HashMap<String, ProfileDefinition>,
+}
+
+/// Used by profiles that do not specify these settings themselves.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Defaults {
+    client: Option<String>,
+    scopes: Option<Vec<String>>,
+    issuer_url: Option<String>,
+    min_validity: Option<u64>,
+}
+
+/// An OAuth client, as registered with the provider.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClientRegistration {
+    client_id: String,
+    client_secret: String,
+    redirect_url: Option<String>,
+    issuer_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileDefinition {
+    client: Option<String>,
+    scopes: Option<Vec<String>>,
+    issuer_url: Option<String>,
+    min_validity: Option<u64>,
+}
+
+impl ConfigToml {
+    /// Read the `config.toml` in the directory. It is optional, so a missing file defines nothing.
+    ///
+    /// The profiles are only resolved when they are used, so that a profile that can not be
+    /// resolved does not keep the others from being used, see [ConfigToml::resolve].
+    pub fn read(dir: &Path) -> Result<ConfigToml, LibError> {
+        let contents = match fs::read_to_string(dir.join(CONFIG_TOML)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(ConfigToml::default()),
+            Err(e) => return Err(e.into()),
+        };
+        toml::from_str(&contents).map_err(|e| LibError::InvalidConfigToml(e.to_string()))
+    }
+
+    /// The names of the profiles that are defined.
+    pub fn profile_names(&self) -> impl Iterator<Item = &str> {
+        self.profiles.keys().map(String::as_str)
+    }
+
+    pub fn defines(&self, name: &str) -> bool {
+        self.profiles.contains_key(name)
+    }
+
+    /// The settings of the profile with the given name, without any tokens,
+    /// or `None` if it is not defined.
+    pub fn resolve(&self, name: &str) -> Result<Option<ConfigFile>, LibError> {
+        let profile = match self.profiles.get(name) {
+            Some(profile) => profile,
+            None => return Ok(None),
+        };
+
+        let client_name = match profile.client.as_ref().or(self.defaults.client.as_ref()) {
+            Some(client_name) => client_name,
+            None => {
+                return Err(LibError::InvalidConfigToml(format!(
+                    "The profile {} has no client",
+                    name
+                )));
+            }
+        };
+        let client = match self.clients.get(client_name) {
+            Some(client) => client,
+            None => {
+                return Err(LibError::InvalidConfigToml(format!(
+                    "The profile {} uses the client {}, which is not defined",
+                    name, client_name
+                )));
+            }
+        };
+        let scopes = match profile.scopes.as_ref().or(self.defaults.scopes.as_ref()) {
+            Some(scopes) if !scopes.is_empty() => scopes,
+            _ => {
+                return Err(LibError::InvalidConfigToml(format!(
+                    "The profile {} has no scopes",
+                    name
+                )));
+            }
+        };
+
+        let mut config = ConfigFile::new(
+            name,
+            &client.client_id,
+            &client.client_secret,
+            scopes,
+            client.redirect_url.as_deref().unwrap_or(DEFAULT_REDIRECT_URL),
+        );
+        config.issuer_url = profile
+            .issuer_url
+            .as_ref()
+            .or(client.issuer_url.as_ref())
+            .or(self.defaults.issuer_url.as_ref())
+            .map_or(GOOGLE_ISSUER_URL, String::as_str)
+            .to_string();
+        config.min_validity = profile.min_validity.or(self.defaults.min_validity);
+
+        Ok(Some(config))
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config_file::{ConfigBasePath, ConfigFile};
+use crate::errors::LibError;
+use crate::profile_name::ProfileName;
+
+/// How often to retry taking the lock of a profile that is locked by someone else.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A lock held on a profile in a [CredentialStore], released when dropped.
+pub type ProfileLock = Box<dyn Send>;
+
+/// Where profiles, and their tokens, are persisted.
+///
+/// [ConfigBasePath] is the default implementation, which saves one JSON file
+/// per profile in a directory. [MemoryStore] keeps the profiles in memory.
+pub trait CredentialStore: Send + Sync {
+    /// Load the profile with the given name, or fail with [LibError::NoSuchConfiguration].
+    fn load(&self, name: &str) -> Result<ConfigFile, LibError>;
+
+    /// Save the profile, replacing any existing profile with the same name.
+    fn save(&self, config: &ConfigFile) -> Result<(), LibError>;
+
+    /// List all the profiles.
+    fn list(&self) -> Result<Vec<ConfigFile>, LibError>;
+
+    /// Delete the profile with the given name, or fail with [LibError::NoSuchConfiguration].
+    fn delete(&self, name: &str) -> Result<(), LibError>;
+
+    /// Try to lock the profile with the given name, so that its tokens are only
+    /// refreshed by one process or task at a time. Returns `None` if it is already locked.
+    fn try_lock(&self, name: &str) -> Result<Option<ProfileLock>, LibError>;
+}
+
+/// Lock the profile with the given name, waiting for anyone else that holds the lock.
+pub async fn lock_profile<S: CredentialStore + ?Sized>(
+    store: &S,
+    name: &str,
+) -> Result<ProfileLock, LibError> {
+    // Poll instead of blocking, so that waiting does not stall the async runtime.
+    loop {
+        match store.try_lock(name)? {
+            Some(lock) => return Ok(lock),
+            None => tokio::time::sleep(LOCK_POLL_INTERVAL).await,
+        }
+    }
+}
+
+impl CredentialStore for ConfigBasePath {
+    fn load(&self, name: &str) -> Result<ConfigFile, LibError> {
+        ConfigFile::read_config(&ProfileName::new(name)?, self)
+    }
+
+    fn save(&self, config: &ConfigFile) -> Result<(), LibError> {
+        config.save_config(self)
+    }
+
+    fn list(&self) -> Result<Vec<ConfigFile>, LibError> {
+        ConfigFile::list_configs(self)
+    }
+
+    fn delete(&self, name: &str) -> Result<(), LibError> {
+        ConfigFile::delete_config(&ProfileName::new(name)?, self).map(|_| ())
+    }
+
+    fn try_lock(&self, name: &str) -> Result<Option<ProfileLock>, LibError> {
+        ConfigFile::try_lock(&ProfileName::new(name)?, self)
+    }
+}
+
+/// A [CredentialStore] that keeps the profiles in memory, for tests and for
+/// programs that persist the profiles themselves.
+#[derive(Default)]
+pub struct MemoryStore {
+    profiles: Mutex<HashMap<String, ConfigFile>>,
+    locked: Arc<Mutex<HashSet<String>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl CredentialStore for MemoryStore {
+    fn load(&self, name: &str) -> Result<ConfigFile, LibError> {
+        self.profiles
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| LibError::NoSuchConfiguration(name.to_string()))
+    }
+
+    fn save(&self, config: &ConfigFile) -> Result<(), LibError> {
+        self.profiles
+            .lock()
+            .unwrap()
+            .insert(config.name.clone(), config.clone());
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<ConfigFile>, LibError> {
+        Ok(self.profiles.lock().unwrap().values().cloned().collect())
+    }
+
+    fn delete(&self, name: &str) -> Result<(), LibError> {
+        match self.profiles.lock().unwrap().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(LibError::NoSuchConfiguration(name.to_string())),
+        }
+    }
+
+    fn try_lock(&self, name: &str) -> Result<Option<ProfileLock>, LibError> {
+        if !self.locked.lock().unwrap().insert(name.to_string()) {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(MemoryLock {
+            name: name.to_string(),
+            locked: self.locked.clone(),
+        })))
+    }
+}
+
+struct MemoryLock {
+    name: String,
+    locked: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Drop for MemoryLock {
+    fn drop(&mut self) {
+        self.locked.lock().unwrap().remove(&self.name);
+    }
+}
@@ -0,0 +1,197 @@
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config_file::ConfigFile;
+use crate::credential_store::CredentialStore;
+use crate::errors::LibError;
+use crate::provider::{discover, http_client, save_login_tokens};
+use openidconnect::core::{CoreClient, CoreDeviceAuthorizationResponse, CoreIdTokenVerifier};
+use openidconnect::{
+    AdditionalProviderMetadata, ClientId, ClientSecret, DeviceAuthorizationUrl, Scope,
+    TokenResponse,
+};
+use serde::{Deserialize, Serialize};
+
+/// The device authorization endpoint is not part of the core discovery
+/// metadata, so it has to be read as additional metadata.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct DeviceEndpointProviderMetadata {
+    device_authorization_endpoint: DeviceAuthorizationUrl,
+}
+
+impl AdditionalProviderMetadata for DeviceEndpointProviderMetadata {}
+
+/// Sign in using the OAuth 2.0 device authorization grant (RFC 8628).
+///
+/// Instead of opening a browser and waiting for a redirect, this prints a
+/// verification URL and a user code, which can be entered on any device.
+/// The token endpoint is then polled until the user has signed in.
+pub async fn google_device_login<S: CredentialStore + ?Sized>(
+    config: &mut ConfigFile,
+    store: &S,
+) -> Result<(), LibError> {
+    device_login(config, store, tokio::time::sleep).await
+}
+
+/// Like [google_device_login], but waits between the polls of the token endpoint with `sleep`.
+async fn device_login<S, F, SF>(config: &mut ConfigFile, store: &S, sleep: F) -> Result<(), LibError>
+where
+    S: CredentialStore + ?Sized,
+    F: Fn(Duration) -> SF,
+    SF: Future<Output = ()>,
+{
+    let google_client_id = ClientId::new(config.client_id.to_string());
+    let google_client_secret = ClientSecret::new(config.client_secret.to_string());
+    let http_client = http_client()?;
+    let provider_metadata = discover::<DeviceEndpointProviderMetadata>(config, &http_client).await?;
+
+    let device_authorization_endpoint = provider_metadata
+        .additional_metadata()
+        .device_authorization_endpoint
+        .clone();
+
+    let client = CoreClient::from_provider_metadata(
+        provider_metadata,
+        google_client_id,
+        Some(google_client_secret),
+    )
+    .set_device_authorization_url(device_authorization_endpoint);
+
+    let request = config
+        .scopes
+        .iter()
+        .fold(client.exchange_device_code(), |request, scope| {
+            request.add_scope(Scope::new(scope.to_string()))
+        });
+
+    let details: CoreDeviceAuthorizationResponse = request
+        .request_async(&http_client)
+        .await
+        .map_err(|_| LibError::OpenIdError("Failed to get a device code".to_string()))?;
+
+    match details.verification_uri_complete() {
+        Some(verification_uri_complete) => eprintln!(
+            "Open this URL in your browser:\n{}\n",
+            verification_uri_complete.secret()
+        ),
+        None => eprintln!(
+            "Open this URL in your browser:\n{}\nand enter the code: {}\n",
+            details.verification_uri().as_str(),
+            details.user_code().secret()
+        ),
+    }
+
+    eprintln!("Waiting for the device to be authorized...");
+
+    // Polls the token endpoint, honoring the interval and any slow_down or
+    // authorization_pending responses, until the device code expires.
+    let token_response = client
+        .exchange_device_access_token(&details)
+        .map_err(|openid_err| LibError::OpenIdError(openid_err.to_string()))?
+        .request_async(&http_client, sleep, None)
+        .await
+        .map_err(|e| LibError::OpenIdError(format!("Failed to access token endpoint: {}", e)))?;
+
+    // The expiry times are relative to the response, which may come long after the user was asked to sign in.
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    // The device flow does not use a nonce.
+    let id_token_verifier: CoreIdTokenVerifier = client.id_token_verifier();
+    let id_token_claims = token_response
+        .id_token()
+        .ok_or(LibError::NoIdToken)?
+        .claims(&id_token_verifier, |_: Option<&_>| Ok(()))
+        .map_err(|_| LibError::OpenIdError("Failed to verify ID token".to_string()))?;
+
+    save_login_tokens(config, store, &token_response, id_token_claims, now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credential_store::MemoryStore;
+    use crate::mock_provider::{issue_tokens, mock_provider, Requests, CLIENT_ID};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    const DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+    fn unix_now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    /// A configuration of a provider whose token endpoint answers the polls with the errors in `pending`,
+    /// in order, and then with tokens. Returns the configuration, and the requests to the provider.
+    async fn device_provider(pending: &'static [&'static str]) -> (ConfigFile, Requests) {
+        let polls = AtomicUsize::new(0);
+        let (issuer, requests) = mock_provider(move |issuer, path, _| match path {
+            "/device" => (200, json!({
+                "device_code": "device-code",
+                "user_code": "ABCD-EFGH",
+                "verification_uri": format!("{}/verify", issuer),
+                "expires_in": 1800,
+                "interval": 1,
+            })),
+            "/token" => match pending.get(polls.fetch_add(1, Ordering::SeqCst)) {
+                Some(error) => (400, json!({"error": error})),
+                None => (200, issue_tokens(issuer)),
+            },
+            _ => (404, json!({})),
+        })
+        .await;
+        let mut config = ConfigFile::new("device", CLIENT_ID, "client-secret", &["openid".to_string()], "");
+        config.issuer_url = issuer;
+        (config, requests)
+    }
+
+    #[tokio::test]
+    async fn polls_the_token_endpoint_until_the_device_is_authorized() {
+        let (mut config, requests) = device_provider(&["authorization_pending", "slow_down"]).await;
+        let store = MemoryStore::new();
+
+        let sleeps = Arc::new(Mutex::new(Vec::new()));
+        let slept = sleeps.clone();
+        let before = unix_now();
+        device_login(&mut config, &store, |interval| {
+            slept.lock().unwrap().push(interval);
+            // Wait a second, so that the expiry times show whether they are relative to the response.
+            tokio::time::sleep(Duration::from_secs(1))
+        })
+        .await
+        .unwrap();
+
+        // The interval of the device authorization response, and 5 seconds more after slow_down.
+        assert_eq!(*sleeps.lock().unwrap(), [Duration::from_secs(1), Duration::from_secs(6)]);
+        let requests = requests.lock().unwrap();
+        let paths: Vec<_> = requests.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["/device", "/token", "/token", "/token"]);
+        for (_, form) in &requests[1..] {
+            assert_eq!(form["grant_type"], DEVICE_CODE_GRANT_TYPE);
+            assert_eq!(form["device_code"], "device-code");
+        }
+
+        let saved = store.load("device").unwrap();
+        assert_eq!(saved.refresh_token.as_deref(), Some("refresh-token"));
+        let access_token = saved.access_token.unwrap();
+        assert_eq!(access_token.secret, "access-token");
+        assert!(access_token.exp >= before + 2 + 3599 && access_token.exp <= unix_now() + 3599);
+    }
+
+    #[tokio::test]
+    async fn stops_polling_on_other_errors() {
+        let (mut config, requests) = device_provider(&["authorization_pending", "access_denied"]).await;
+        let store = MemoryStore::new();
+
+        let result = device_login(&mut config, &store, |_| std::future::ready(())).await;
+        match result {
+            Err(LibError::OpenIdError(message)) => assert!(message.contains("access_denied"), "{}", message),
+            _ => panic!("expected OpenIdError"),
+        }
+        assert_eq!(requests.lock().unwrap().len(), 3);
+        assert!(store.load("device").is_err());
+    }
+}
@@ -0,0 +1,261 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::LibError;
+
+/// The `version` of an encrypted configuration file. It is kept apart from the
+/// versions of plaintext configuration files, so that those can evolve independently.
+///
+/// Version 1000 files only authenticate the name of the configuration. Since version 1001
+/// the kind of the file and the version are authenticated as well.
+pub const ENCRYPTED_CONFIG_VERSION: u32 = 1001;
+
+/// The first version of encrypted configuration files.
+pub(crate) const FIRST_ENCRYPTED_CONFIG_VERSION: u32 = 1000;
+
+const KDF: &str = "argon2id";
+const CIPHER: &str = "xchacha20poly1305";
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 24;
+
+/// How many times the default Argon2 costs a file may ask for. The costs are read before the file is
+/// authenticated, so a tampered file could otherwise make the key derivation take gigabytes, or hours.
+const MAX_COST_FACTOR: u32 = 8;
+
+/// Which file of a configuration is encrypted, so that a token cache can not be passed off
+/// as a profile file, or the other way around.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum FileKind {
+    Profile,
+    TokenCache,
+}
+
+impl FileKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            FileKind::Profile => "profile",
+            FileKind::TokenCache => "token_cache",
+        }
+    }
+}
+
+/// An encrypted configuration or token cache file. The name of the configuration is stored
+/// in plain text, so that the right passphrase can be asked for, and is authenticated along
+/// with the ciphertext, the kind of the file and the version.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct EncryptedConfigFile {
+    pub version: u32,
+    pub name: String,
+    kdf: String,
+    memory_cost: u32,
+    time_cost: u32,
+    parallelism: u32,
+    salt: String,
+    cipher: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+impl EncryptedConfigFile {
+    /// Encrypt the contents of a file with a key derived from the passphrase.
+    pub fn encrypt(
+        name: &str,
+        kind: FileKind,
+        contents: &serde_json::Value,
+        passphrase: &str,
+    ) -> Result<EncryptedConfigFile, LibError> {
+        if passphrase.is_empty() {
+            return Err(LibError::PassphraseRequired(name.to_string()));
+        }
+
+        let params = Params::default();
+        let mut salt = [0u8; SALT_LENGTH];
+        let mut nonce = [0u8; NONCE_LENGTH];
+        getrandom::getrandom(&mut salt).map_err(|e| LibError::EncryptionError(e.to_string()))?;
+        getrandom::getrandom(&mut nonce).map_err(|e| LibError::EncryptionError(e.to_string()))?;
+
+        let mut encrypted = EncryptedConfigFile {
+            version: ENCRYPTED_CONFIG_VERSION,
+            name: name.to_string(),
+            kdf: KDF.to_string(),
+            memory_cost: params.m_cost(),
+            time_cost: params.t_cost(),
+            parallelism: params.p_cost(),
+            salt: BASE64.encode(salt),
+            cipher: CIPHER.to_string(),
+            nonce: BASE64.encode(nonce),
+            ciphertext: String::new(),
+        };
+
+        let cipher = encrypted.cipher(passphrase)?;
+        let plaintext = serde_json::to_vec(contents)?;
+        let ciphertext = cipher
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload { msg: &plaintext, aad: &encrypted.aad(kind) },
+            )
+            .map_err(|e| LibError::EncryptionError(e.to_string()))?;
+        encrypted.ciphertext = BASE64.encode(ciphertext);
+
+        Ok(encrypted)
+    }
+
+    /// Decrypt the contents of the file, failing with [LibError::DecryptionFailed]
+    /// if the passphrase is wrong, the file has been tampered with, or it is another kind of file.
+    pub fn decrypt(&self, kind: FileKind, passphrase: &str) -> Result<serde_json::Value, LibError> {
+        if !(FIRST_ENCRYPTED_CONFIG_VERSION..=ENCRYPTED_CONFIG_VERSION).contains(&self.version) {
+            return Err(LibError::UnsupportedConfigVersion(self.name.clone(), self.version as u64));
+        }
+        if self.kdf != KDF || self.cipher != CIPHER {
+            return Err(LibError::EncryptionError(format!(
+                "Unsupported encryption {} with {}",
+                self.cipher, self.kdf
+            )));
+        }
+
+        let decryption_failed = || LibError::DecryptionFailed(self.name.clone());
+        let nonce = BASE64.decode(&self.nonce).map_err(|_| decryption_failed())?;
+        let ciphertext = BASE64.decode(&self.ciphertext).map_err(|_| decryption_failed())?;
+        if nonce.len() != NONCE_LENGTH {
+            return Err(decryption_failed());
+        }
+
+        let plaintext = self
+            .cipher(passphrase)?
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                Payload { msg: &ciphertext, aad: &self.aad(kind) },
+            )
+            .map_err(|_| decryption_failed())?;
+
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+
+    /// The data that is authenticated along with the ciphertext.
+    fn aad(&self, kind: FileKind) -> Vec<u8> {
+        if self.version == FIRST_ENCRYPTED_CONFIG_VERSION {
+            return self.name.as_bytes().to_vec();
+        }
+        format!("googauth\0{}\0{}\0{}", self.version, kind.as_str(), self.name).into_bytes()
+    }
+
+    fn cipher(&self, passphrase: &str) -> Result<XChaCha20Poly1305, LibError> {
+        let salt = BASE64
+            .decode(&self.salt)
+            .map_err(|_| LibError::DecryptionFailed(self.name.clone()))?;
+        if self.memory_cost > Params::DEFAULT_M_COST * MAX_COST_FACTOR
+            || self.time_cost > Params::DEFAULT_T_COST * MAX_COST_FACTOR
+            || self.parallelism > Params::DEFAULT_P_COST * MAX_COST_FACTOR
+        {
+            return Err(LibError::EncryptionError(format!(
+                "Unsupported key derivation costs: {} KiB of memory, {} iterations and {} lanes",
+                self.memory_cost, self.time_cost, self.parallelism
+            )));
+        }
+        let params = Params::new(self.memory_cost, self.time_cost, self.parallelism, Some(32))
+            .map_err(|e| LibError::EncryptionError(e.to_string()))?;
+
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| LibError::EncryptionError(e.to_string()))?;
+
+        Ok(XChaCha20Poly1305::new(&key.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn contents() -> serde_json::Value {
+        json!({"version": 2, "name": "myprofile", "client_secret": "secret"})
+    }
+
+    #[test]
+    fn decrypts_what_was_encrypted() {
+        let encrypted = EncryptedConfigFile::encrypt("myprofile", FileKind::Profile, &contents(), "correct-horse").unwrap();
+        assert_eq!(encrypted.version, ENCRYPTED_CONFIG_VERSION);
+        assert!(!encrypted.ciphertext.contains("secret"));
+
+        let encrypted: EncryptedConfigFile =
+            serde_json::from_value(serde_json::to_value(&encrypted).unwrap()).unwrap();
+        assert_eq!(encrypted.decrypt(FileKind::Profile, "correct-horse").unwrap(), contents());
+    }
+
+    #[test]
+    fn fails_with_a_wrong_passphrase() {
+        let encrypted = EncryptedConfigFile::encrypt("myprofile", FileKind::Profile, &contents(), "correct-horse").unwrap();
+        assert!(matches!(
+            encrypted.decrypt(FileKind::Profile, "battery-staple"),
+            Err(LibError::DecryptionFailed(name)) if name == "myprofile"
+        ));
+    }
+
+    #[test]
+    fn fails_when_the_name_is_changed() {
+        let mut encrypted = EncryptedConfigFile::encrypt("myprofile", FileKind::Profile, &contents(), "correct-horse").unwrap();
+        encrypted.name = "other".to_string();
+        assert!(matches!(
+            encrypted.decrypt(FileKind::Profile, "correct-horse"),
+            Err(LibError::DecryptionFailed(_))
+        ));
+    }
+
+    #[test]
+    fn fails_for_another_kind_of_file() {
+        let encrypted = EncryptedConfigFile::encrypt("myprofile", FileKind::TokenCache, &contents(), "correct-horse").unwrap();
+        assert!(matches!(
+            encrypted.decrypt(FileKind::Profile, "correct-horse"),
+            Err(LibError::DecryptionFailed(_))
+        ));
+        assert_eq!(encrypted.decrypt(FileKind::TokenCache, "correct-horse").unwrap(), contents());
+    }
+
+    #[test]
+    fn fails_when_the_version_is_changed() {
+        let mut encrypted = EncryptedConfigFile::encrypt("myprofile", FileKind::Profile, &contents(), "correct-horse").unwrap();
+        encrypted.version = FIRST_ENCRYPTED_CONFIG_VERSION;
+        assert!(matches!(
+            encrypted.decrypt(FileKind::Profile, "correct-horse"),
+            Err(LibError::DecryptionFailed(_))
+        ));
+
+        encrypted.version = ENCRYPTED_CONFIG_VERSION + 1;
+        assert!(matches!(
+            encrypted.decrypt(FileKind::Profile, "correct-horse"),
+            Err(LibError::UnsupportedConfigVersion(_, _))
+        ));
+    }
+
+    #[test]
+    fn rejects_excessive_key_derivation_costs() {
+        let encrypted = EncryptedConfigFile::encrypt("myprofile", FileKind::Profile, &contents(), "correct-horse").unwrap();
+        for tamper in [
+            |encrypted: &mut EncryptedConfigFile| encrypted.memory_cost = 4 * 1024 * 1024,
+            |encrypted: &mut EncryptedConfigFile| encrypted.time_cost = u32::MAX,
+            |encrypted: &mut EncryptedConfigFile| encrypted.parallelism = 1024,
+        ] {
+            let mut tampered: EncryptedConfigFile =
+                serde_json::from_value(serde_json::to_value(&encrypted).unwrap()).unwrap();
+            tamper(&mut tampered);
+            assert!(matches!(
+                tampered.decrypt(FileKind::Profile, "correct-horse"),
+                Err(LibError::EncryptionError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_an_empty_passphrase() {
+        assert!(matches!(
+            EncryptedConfigFile::encrypt("myprofile", FileKind::Profile, &contents(), ""),
+            Err(LibError::PassphraseRequired(_))
+        ));
+    }
+}
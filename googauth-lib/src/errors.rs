@@ -5,6 +5,8 @@ use std::path::PathBuf;
 pub enum LibError {
     #[error("No such configuration: {0}")]
     NoSuchConfiguration(String),
+    #[error("A configuration named {0} already exists")]
+    ConfigurationAlreadyExists(String),
     #[error("Can not find home directory")]
     HomeDirectoryNotFound,
     #[error("Configs directory {0} is not a directory")]
@@ -25,8 +27,8 @@ pub enum LibError {
     NoRefreshToken,
     #[error("Could not refresh token")]
     CouldNotRefreshToken,
-    #[error("Could not read claims")]
-    CouldNotReadClaims,
+    #[error("Could not read claims: {0}")]
+    CouldNotReadClaims(String),
     #[error("There were no scopes in the response")]
     NoScopes,
     #[error("Could not get a response from the login flow")]
@@ -39,4 +41,91 @@ pub enum LibError {
     OpenIdError(String),
     #[error("No local port in redirect URL")]
     NoRedirectPortError,
+    #[error("No authorization code was found in the redirect")]
+    NoAuthorizationCode,
+    #[error("Access was denied: {0}")]
+    AccessDenied(String),
+    #[error("The authorization server returned an error: {0}")]
+    AuthorizationError(String),
+    #[error("Timed out waiting for the browser to sign in")]
+    RedirectTimeout,
+    #[error("The provider does not have a revocation endpoint")]
+    NoRevocationEndpoint,
+    #[error("Could not revoke token: {0}")]
+    CouldNotRevokeToken(String),
+    #[error("A passphrase is required to use the encrypted configuration {0}")]
+    PassphraseRequired(String),
+    #[error("Could not decrypt the configuration {0}. Is the passphrase correct?")]
+    DecryptionFailed(String),
+    #[error("Encryption error: {0}")]
+    EncryptionError(String),
+    #[error("The configuration {0} has the unsupported version {1}. Was it saved by a newer version of googauth?")]
+    UnsupportedConfigVersion(String, u64),
+    #[error("Invalid profile name {0:?}. Profile names can not be empty, be config.toml or tokens, start with a dot, or contain path separators or control characters")]
+    InvalidProfileName(String),
+    #[error("The profile file {0} has the profile name {1}. Rename the file, or the profile in it, so that they match")]
+    ProfileNameMismatch(String, String),
+    #[error("Invalid config.toml: {0}")]
+    InvalidConfigToml(String),
+    #[error("Invalid client secrets file: {0}")]
+    InvalidClientSecrets(String),
+    #[error("Invalid Application Default Credentials: {0}")]
+    InvalidApplicationDefaultCredentials(String),
+    #[error("Invalid service account key: {0}")]
+    InvalidServiceAccountKey(String),
+    #[error("The configuration {0} does not have a service account key")]
+    NotAServiceAccount(String),
+    #[error("The service account configuration {0} has no target audience, which is needed to get ID tokens")]
+    NoTargetAudience(String),
+    #[error("The self-signed access tokens of the configuration {0} need an audience or scopes")]
+    NoJwtAudience(String),
+    #[error("The configuration {0} signs its access tokens itself, which can not act as another user")]
+    SelfSignedJwtSubject(String),
+}
+
+impl LibError {
+    /// A stable, machine-readable code for the error, e.g. `no_such_configuration`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            LibError::NoSuchConfiguration(_) => "no_such_configuration",
+            LibError::ConfigurationAlreadyExists(_) => "configuration_already_exists",
+            LibError::HomeDirectoryNotFound => "home_directory_not_found",
+            LibError::ConfigsDirectoryNotADirectory(_) => "configs_directory_not_a_directory",
+            LibError::IoError(_) => "io_error",
+            LibError::FilenameError => "filename_error",
+            LibError::JsonError(_) => "json_error",
+            LibError::UrlError(_) => "url_error",
+            LibError::TokenCsrfError => "token_csrf_error",
+            LibError::NoIdToken => "no_id_token",
+            LibError::NoRefreshToken => "no_refresh_token",
+            LibError::CouldNotRefreshToken => "could_not_refresh_token",
+            LibError::CouldNotReadClaims(_) => "could_not_read_claims",
+            LibError::NoScopes => "no_scopes",
+            LibError::NoResponse => "no_response",
+            LibError::NoRefreshTokenForConfig(_) => "no_refresh_token_for_config",
+            LibError::CouldNotReadConfigCorrupt(_) => "could_not_read_config_corrupt",
+            LibError::OpenIdError(_) => "open_id_error",
+            LibError::NoRedirectPortError => "no_redirect_port_error",
+            LibError::NoAuthorizationCode => "no_authorization_code",
+            LibError::AccessDenied(_) => "access_denied",
+            LibError::AuthorizationError(_) => "authorization_error",
+            LibError::RedirectTimeout => "redirect_timeout",
+            LibError::NoRevocationEndpoint => "no_revocation_endpoint",
+            LibError::CouldNotRevokeToken(_) => "could_not_revoke_token",
+            LibError::PassphraseRequired(_) => "passphrase_required",
+            LibError::DecryptionFailed(_) => "decryption_failed",
+            LibError::EncryptionError(_) => "encryption_error",
+            LibError::UnsupportedConfigVersion(_, _) => "unsupported_config_version",
+            LibError::InvalidProfileName(_) => "invalid_profile_name",
+            LibError::ProfileNameMismatch(_, _) => "profile_name_mismatch",
+            LibError::InvalidConfigToml(_) => "invalid_config_toml",
+            LibError::InvalidClientSecrets(_) => "invalid_client_secrets",
+            LibError::InvalidApplicationDefaultCredentials(_) => "invalid_application_default_credentials",
+            LibError::InvalidServiceAccountKey(_) => "invalid_service_account_key",
+            LibError::NotAServiceAccount(_) => "not_a_service_account",
+            LibError::NoTargetAudience(_) => "no_target_audience",
+            LibError::NoJwtAudience(_) => "no_jwt_audience",
+            LibError::SelfSignedJwtSubject(_) => "self_signed_jwt_subject",
+        }
+    }
 }
@@ -1,25 +1,68 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+pub use adc::{default_adc_path, ADC_FILE_NAME, ADC_SCOPES};
 pub use config_file::*;
-pub use login_flow::google_login;
+pub use config_toml::CONFIG_TOML;
+pub use credential_store::{lock_profile, CredentialStore, MemoryStore, ProfileLock};
+pub use device_flow::google_device_login;
+pub use encryption::ENCRYPTED_CONFIG_VERSION;
+pub use login_flow::{google_login, google_login_with_timeout, google_manual_login};
+pub use profile_name::ProfileName;
+pub use redirect_server::DEFAULT_REDIRECT_TIMEOUT;
 pub use refresh_flow::refresh_google_login;
+pub use revoke_flow::revoke_google_login;
+pub use service_account::CLOUD_PLATFORM_SCOPE;
+pub use service_account_flow::service_account_login;
 
 pub use crate::errors::LibError;
 
+use crate::service_account_flow::service_account_access_token_as;
+
+mod adc;
+mod client_secrets;
 mod config_file;
+mod config_toml;
+mod credential_store;
+mod device_flow;
+mod encryption;
 mod errors;
 mod login_flow;
+#[cfg(test)]
+mod mock_provider;
+mod profile_name;
+mod provider;
+mod redirect_server;
 mod refresh_flow;
+mod revoke_flow;
+mod service_account;
+mod service_account_flow;
 
-/// Given a config name, that has been previously saved by [config_file::ConfigFile],
+/// Given a config name, that has been previously saved in the [CredentialStore],
 /// fetch the access token, potentially refreshing it if needed.
-pub async fn get_access_token_from_config(
+pub async fn get_access_token_from_config<S: CredentialStore + ?Sized>(
+    config_name: &str,
+    store: &S,
+) -> Result<Token, LibError> {
+    get_access_token_from_config_with_min_validity(config_name, store, None).await
+}
+
+/// Like [get_access_token_from_config], but refresh the access token if it expires within
+/// `min_validity`. If `min_validity` is `None` the default of the configuration is used.
+pub async fn get_access_token_from_config_with_min_validity<S: CredentialStore + ?Sized>(
     config_name: &str,
-    config_base_path: &ConfigBasePath,
+    store: &S,
+    min_validity: Option<Duration>,
 ) -> Result<Token, LibError> {
-    let mut config = ConfigFile::read_config(config_name, config_base_path)?;
+    let mut config = store.load(config_name)?;
 
-    check_token(config.access_token.clone(), &mut config, config_base_path).await?;
+    let min_validity = min_validity.unwrap_or_else(|| config.default_min_validity());
+    check_token_with_min_validity(
+        config.access_token.clone(),
+        &mut config,
+        store,
+        min_validity,
+    )
+    .await?;
 
     match &config.access_token {
         Some(access_token) => Ok(access_token.clone()),
@@ -27,26 +70,227 @@ pub async fn get_access_token_from_config(
     }
 }
 
+/// Like [get_access_token_from_config_with_min_validity], but for a service account configuration that acts as
+/// the `subject` user, with domain-wide delegation. The tokens of each subject are cached separately,
+/// in [ConfigFile::subject_access_tokens], except those of the [ConfigFile::subject] of the configuration.
+pub async fn get_access_token_from_config_as_subject<S: CredentialStore + ?Sized>(
+    config_name: &str,
+    store: &S,
+    subject: &str,
+    min_validity: Option<Duration>,
+) -> Result<Token, LibError> {
+    let config = store.load(config_name)?;
+    if config.service_account_key.is_none() {
+        return Err(LibError::NotAServiceAccount(config.name));
+    }
+    if config.subject.as_deref() == Some(subject) {
+        return get_access_token_from_config_with_min_validity(config_name, store, min_validity).await;
+    }
+
+    let min_validity = min_validity.unwrap_or_else(|| config.default_min_validity());
+    let now = unix_now();
+    let cached_token = |config: &ConfigFile| {
+        config
+            .subject_access_tokens
+            .get(subject)
+            .filter(|token| !token.expires_within(min_validity, now))
+            .cloned()
+    };
+    if let Some(token) = cached_token(&config) {
+        return Ok(token);
+    }
+
+    let _lock = lock_profile(store, &config.name).await?;
+    // Load the configuration again, since another process may have saved tokens while we waited for the lock.
+    let mut config = store.load(config_name)?;
+    if let Some(token) = cached_token(&config) {
+        return Ok(token);
+    }
+    let token = service_account_access_token_as(&config, subject).await?;
+    // Drop the tokens of other subjects that have expired, so that the cache does not keep growing.
+    config.subject_access_tokens.retain(|_, token| !token.expires_within(Duration::ZERO, now));
+    config.subject_access_tokens.insert(subject.to_string(), token.clone());
+    store.save(&config)?;
+    Ok(token)
+}
+
 /// Given an optional [config_file::Token] and a [config_file::ConfigFile],
 /// check if it's valid and potentially refresh it if it is not.
-pub async fn check_token(
+///
+/// The refresh is done while holding the [ProfileLock] of the
+/// configuration, and is skipped if another process refreshed the tokens first.
+pub async fn check_token<S: CredentialStore + ?Sized>(
+    token: Option<Token>,
+    config: &mut ConfigFile,
+    store: &S,
+) -> Result<(), LibError> {
+    let min_validity = config.default_min_validity();
+    check_token_with_min_validity(token, config, store, min_validity).await
+}
+
+/// Like [check_token], but also refresh the token if it expires within `min_validity`.
+/// A token without a known expiry time is never refreshed, while a missing token always is.
+pub async fn check_token_with_min_validity<S: CredentialStore + ?Sized>(
     token: Option<Token>,
     config: &mut ConfigFile,
-    config_base_path: &ConfigBasePath,
+    store: &S,
+    min_validity: Duration,
 ) -> Result<(), LibError> {
-    let now = SystemTime::now()
+    if needs_refresh(token.as_ref(), min_validity, unix_now()) {
+        refresh_tokens(config, store).await?;
+    }
+    Ok(())
+}
+
+/// Like [check_token_with_min_validity], but for both the access token and the ID token of the configuration.
+/// They are refreshed together, so at most one refresh is done. The ID token is not checked if the
+/// configuration does not get one, see [ConfigFile::has_id_tokens].
+pub async fn check_tokens_with_min_validity<S: CredentialStore + ?Sized>(
+    config: &mut ConfigFile,
+    store: &S,
+    min_validity: Duration,
+) -> Result<(), LibError> {
+    let now = unix_now();
+    if needs_refresh(config.access_token.as_ref(), min_validity, now)
+        || (config.has_id_tokens() && needs_refresh(config.id_token.as_ref(), min_validity, now))
+    {
+        refresh_tokens(config, store).await?;
+    }
+    Ok(())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
-        .as_secs();
+        .as_secs()
+}
 
-    let token_expiration = match token {
-        Some(token) => token.exp,
-        None => 0,
-    };
+fn needs_refresh(token: Option<&Token>, min_validity: Duration, now: u64) -> bool {
+    match token {
+        Some(token) => token.expires_within(min_validity, now),
+        None => true,
+    }
+}
+
+/// Refresh the tokens of the configuration while holding its [ProfileLock].
+async fn refresh_tokens<S: CredentialStore + ?Sized>(config: &mut ConfigFile, store: &S) -> Result<(), LibError> {
+    let _lock = lock_profile(store, &config.name).await?;
 
-    if token_expiration < now {
-        refresh_google_login(config, config_base_path).await?;
+    // Another process may have refreshed the tokens while we waited for the lock.
+    if let Ok(saved_config) = store.load(&config.name)
+        && saved_config.has_newer_tokens_than(config)
+    {
+        *config = saved_config;
+        return Ok(());
     }
 
-    Ok(())
+    if config.service_account_key.is_some() {
+        service_account_login(config, store).await
+    } else {
+        refresh_google_login(config, store).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_provider::{issue_tokens, mock_provider, Requests, CLIENT_ID};
+    use serde_json::Value;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+    const NOW: u64 = 1700000000;
+
+    #[test]
+    fn tokens_expire_within_the_minimum_validity() {
+        let token = Token::new("token".to_string(), NOW + 60);
+        assert!(!token.expires_within(Duration::ZERO, NOW));
+        assert!(!token.expires_within(MINUTE, NOW));
+        assert!(token.expires_within(MINUTE + Duration::from_secs(1), NOW));
+        assert!(!token.expires_within(Duration::ZERO, NOW + 60));
+        assert!(token.expires_within(Duration::ZERO, NOW + 61));
+        assert!(token.expires_within(Duration::MAX, NOW));
+    }
+
+    #[test]
+    fn tokens_without_an_expiry_do_not_expire() {
+        let token = Token::new("token".to_string(), 0);
+        assert!(!token.has_expiry());
+        assert!(!token.expires_within(Duration::MAX, NOW));
+        assert!(!needs_refresh(Some(&token), Duration::MAX, NOW));
+    }
+
+    #[test]
+    fn refreshes_missing_and_expiring_tokens() {
+        let token = Token::new("token".to_string(), NOW + 60);
+        assert!(needs_refresh(None, Duration::ZERO, NOW));
+        assert!(!needs_refresh(Some(&token), MINUTE, NOW));
+        assert!(needs_refresh(Some(&token), MINUTE + Duration::from_secs(1), NOW));
+    }
+
+    /// A configuration of a provider that answers refreshes with `tokens`, saved in the store with
+    /// tokens that expire in `expires_in` seconds. Returns the configuration, and the requests to the provider.
+    async fn signed_in_config(store: &MemoryStore, tokens: fn(&str) -> Value, expires_in: u64) -> (ConfigFile, Requests) {
+        let (issuer, requests) = mock_provider(move |issuer, _, _| (200, tokens(issuer))).await;
+        let mut config = ConfigFile::new("myprofile", CLIENT_ID, "client-secret", &["openid".to_string()], "");
+        config.issuer_url = issuer;
+        config.refresh_token = Some("refresh-token".to_string());
+        config.access_token = Some(Token::new("old-access-token".to_string(), unix_now() + expires_in));
+        config.id_token = Some(Token::new("old-id-token".to_string(), unix_now() + expires_in));
+        store.save(&config).unwrap();
+        (config, requests)
+    }
+
+    #[tokio::test]
+    async fn refreshes_tokens_that_expire_within_the_minimum_validity() {
+        let store = MemoryStore::new();
+        let (mut config, requests) = signed_in_config(&store, issue_tokens, 600).await;
+
+        // The tokens are valid for 10 minutes, which is enough.
+        check_tokens_with_min_validity(&mut config, &store, 5 * MINUTE).await.unwrap();
+        let token = get_access_token_from_config_with_min_validity("myprofile", &store, Some(5 * MINUTE))
+            .await
+            .unwrap();
+        assert_eq!(token.secret, "old-access-token");
+        assert!(requests.lock().unwrap().is_empty());
+
+        let token = get_access_token_from_config_with_min_validity("myprofile", &store, Some(15 * MINUTE))
+            .await
+            .unwrap();
+        assert_eq!(token.secret, "access-token");
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0, "/token");
+        assert_eq!(requests[0].1["grant_type"], "refresh_token");
+    }
+
+    #[tokio::test]
+    async fn refreshes_expiring_id_tokens() {
+        let store = MemoryStore::new();
+        let (mut config, requests) = signed_in_config(&store, issue_tokens, 600).await;
+        config.id_token = Some(Token::new("old-id-token".to_string(), unix_now() + 60));
+        store.save(&config).unwrap();
+
+        check_tokens_with_min_validity(&mut config, &store, 5 * MINUTE).await.unwrap();
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert_eq!(config.access_token.unwrap().secret, "access-token");
+        assert_ne!(config.id_token.unwrap().secret, "old-id-token");
+    }
+
+    #[tokio::test]
+    async fn assumes_an_hour_for_access_tokens_without_an_expiry() {
+        let without_expiry = |issuer: &str| {
+            let mut tokens = issue_tokens(issuer);
+            tokens.as_object_mut().unwrap().remove("expires_in");
+            tokens
+        };
+        let store = MemoryStore::new();
+        let (mut config, _) = signed_in_config(&store, without_expiry, 600).await;
+
+        let before = unix_now();
+        check_token(None, &mut config, &store).await.unwrap();
+        let access_token = store.load("myprofile").unwrap().access_token.unwrap();
+        assert_eq!(access_token.secret, "access-token");
+        assert!(access_token.exp >= before + 3600 && access_token.exp <= unix_now() + 3600);
+    }
 }
@@ -1,37 +1,78 @@
-use std::io::{BufRead, BufReader, Write};
-use std::net::TcpListener;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::io::BufRead;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::config_file::{ConfigBasePath, ConfigFile, Token};
+use crate::config_file::ConfigFile;
+use crate::credential_store::CredentialStore;
 use crate::errors::LibError;
+use crate::provider::{discover, http_client, save_login_tokens};
+use crate::redirect_server::{receive_redirect, redirect_result, DEFAULT_REDIRECT_TIMEOUT};
 use openidconnect::core::{
-    CoreAuthPrompt, CoreClient, CoreIdTokenClaims, CoreIdTokenVerifier, CoreProviderMetadata,
-    CoreResponseType,
+    CoreAuthPrompt, CoreClient, CoreIdTokenClaims, CoreIdTokenVerifier, CoreResponseType,
 };
 use openidconnect::{
-    reqwest, AuthenticationFlow, AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl,
-    Nonce, OAuth2TokenResponse, PkceCodeChallenge, RedirectUrl, Scope, TokenResponse,
+    AuthenticationFlow, AuthorizationCode, ClientId, ClientSecret, CsrfToken,
+    EmptyAdditionalProviderMetadata, Nonce, PkceCodeChallenge, RedirectUrl, Scope,
 };
-use url::Url;
+use tokio::net::TcpListener;
+use url::{Host, Url};
+
+/// How the authorization code is received after the user has signed in.
+enum RedirectMode {
+    /// Open a web browser and listen for the redirect on the local port in the redirect URL,
+    /// giving up after the timeout.
+    Listen(Duration),
+    /// Print the authorize URL and read the redirect URL (or the code) from stdin.
+    Manual,
+}
+
+/// Sign in by opening a web browser, and receive the redirect on the
+/// local port given in the configuration's redirect URL.
+///
+/// If the port is 0, e.g. `http://127.0.0.1:0/`, a free port is picked by the OS.
+pub async fn google_login<S: CredentialStore + ?Sized>(
+    config: &mut ConfigFile,
+    store: &S,
+) -> Result<(), LibError> {
+    google_login_with_timeout(config, store, DEFAULT_REDIRECT_TIMEOUT).await
+}
+
+/// Like [google_login], but gives up with [LibError::RedirectTimeout] if the
+/// browser has not been redirected back within `timeout`.
+pub async fn google_login_with_timeout<S: CredentialStore + ?Sized>(
+    config: &mut ConfigFile,
+    store: &S,
+    timeout: Duration,
+) -> Result<(), LibError> {
+    login(config, store, RedirectMode::Listen(timeout)).await
+}
+
+/// Sign in without a web browser or a redirect listener.
+///
+/// The authorize URL is printed, and once the user has signed in the
+/// URL that the browser was redirected to (or only the code) is read from stdin.
+/// Useful when the redirect port can not be reached, e.g. on a jump host.
+pub async fn google_manual_login<S: CredentialStore + ?Sized>(
+    config: &mut ConfigFile,
+    store: &S,
+) -> Result<(), LibError> {
+    login(config, store, RedirectMode::Manual).await
+}
 
-pub async fn google_login(
+async fn login<S: CredentialStore + ?Sized>(
     config: &mut ConfigFile,
-    config_base_path: &ConfigBasePath,
+    store: &S,
+    mode: RedirectMode,
 ) -> Result<(), LibError> {
     let google_client_id = ClientId::new(config.client_id.to_string());
     let google_client_secret = ClientSecret::new(config.client_secret.to_string());
-    let issuer_url = IssuerUrl::new("https://accounts.google.com".to_string())?;
-    let redirect_url = Url::parse(&config.redirect_url)?;
-    let http_client = reqwest::ClientBuilder::new()
-        // Following redirects opens the client up to SSRF vulnerabilities.
-        .redirect(reqwest::redirect::Policy::none())
-        .build()
-        .map_err(|openid_error| LibError::OpenIdError(openid_error.to_string()))?;
-
-    // Fetch Google's OpenID Connect discovery document.
-    let provider_metadata = CoreProviderMetadata::discover_async(issuer_url, &http_client)
-        .await
-        .map_err(|_| LibError::OpenIdError("Failed to discover OpenID Provider".to_string()))?;
+    let mut redirect_url = Url::parse(&config.redirect_url)?;
+    let http_client = http_client()?;
+    let provider_metadata = discover::<EmptyAdditionalProviderMetadata>(config, &http_client).await?;
+
+    // The listener is bound before building the authorize request, since
+    // the port assigned by the OS is part of the redirect URL.
+    let listener = redirect_listener(&mode, &mut redirect_url).await?;
 
     let client = CoreClient::from_provider_metadata(
         provider_metadata,
@@ -40,13 +81,6 @@ pub async fn google_login(
     )
     .set_redirect_uri(RedirectUrl::new(redirect_url.to_string())?);
 
-    let port = match redirect_url.port() {
-        Some(port) => port,
-        None => {
-            return Err(LibError::NoRedirectPortError);
-        }
-    };
-
     let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
 
     let request = client.authorize_url(
@@ -68,115 +102,267 @@ pub async fn google_login(
 
     let authorize_url_string = authorize_url.to_string();
 
-    webbrowser::open(&authorize_url_string)?;
+    let (code, state) = match listener {
+        Some((listener, timeout)) => {
+            webbrowser::open(&authorize_url_string)?;
 
-    println!(
-        "If the web browser did not open automatically, you can open this URL in your browser:\n{}\n",
-        &authorize_url_string
-    );
+            eprintln!(
+                "If the web browser did not open automatically, you can open this URL in your browser:\n{}\n",
+                &authorize_url_string
+            );
+
+            eprintln!("Waiting for the browser to sign you in...");
 
-    println!("Waiting for the browser to sign you in...");
+            let (code, state) = receive_redirect(listener, redirect_url.path(), timeout).await?;
+            (code, Some(state))
+        }
+        None => {
+            eprintln!(
+                "Open this URL in a browser on any machine and sign in:\n{}\n",
+                &authorize_url_string
+            );
+            eprintln!(
+                "The browser will then be redirected to {}, which will probably fail to load.",
+                &redirect_url
+            );
+            eprintln!("Copy the full URL from the browser's address bar (or only the code) and paste it here:");
+
+            read_pasted_redirect()?
+        }
+    };
+
+    check_state(state.as_ref(), &csrf_state)?;
 
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_secs();
 
-    // A very naive implementation of the redirect server.
-    let listener = TcpListener::bind(format!("127.0.0.1:{port}")).unwrap();
-    if let Some(mut stream) = listener.incoming().flatten().next() {
-        let code;
-        let state;
-        {
-            let mut reader = BufReader::new(&stream);
-
-            let mut request_line = String::new();
-            reader.read_line(&mut request_line).unwrap();
-
-            let redirect_url = request_line.split_whitespace().nth(1).unwrap();
-            let url = Url::parse(&("http://localhost".to_string() + redirect_url)).unwrap();
-
-            let code_pair = url
-                .query_pairs()
-                .find(|pair| {
-                    let &(ref key, _) = pair;
-                    key == "code"
-                })
-                .unwrap();
-
-            let (_, value) = code_pair;
-            code = AuthorizationCode::new(value.into_owned());
-
-            let state_pair = url
-                .query_pairs()
-                .find(|pair| {
-                    let &(ref key, _) = pair;
-                    key == "state"
-                })
-                .unwrap();
-
-            let (_, value) = state_pair;
-            state = CsrfToken::new(value.into_owned());
+    // Exchange the code with a token.
+    let token_response = client
+        .exchange_code(code)
+        .map_err(|openid_err| LibError::OpenIdError(openid_err.to_string()))?
+        .set_pkce_verifier(pkce_verifier)
+        .request_async(&http_client)
+        .await
+        .map_err(|e| {
+            eprintln!("{:?}", e);
+            LibError::OpenIdError("Failed to access token endpoint".to_string())
+        })?;
+
+    let id_token_verifier: CoreIdTokenVerifier = client.id_token_verifier();
+    let id_token_claims: &CoreIdTokenClaims = token_response
+        .extra_fields()
+        .id_token()
+        .ok_or(LibError::NoIdToken)?
+        .claims(&id_token_verifier, &nonce)
+        .map_err(|_| LibError::OpenIdError("Failed to verify ID token".to_string()))?;
+
+    save_login_tokens(config, store, &token_response, id_token_claims, now)
+}
+
+/// Bind the redirect listener of the mode, if it has one, and return it with its timeout.
+///
+/// Without a listener a redirect URL with port 0 still gets a free port, since the
+/// provider would otherwise redirect to port 0.
+async fn redirect_listener(
+    mode: &RedirectMode,
+    redirect_url: &mut Url,
+) -> Result<Option<(TcpListener, Duration)>, LibError> {
+    match mode {
+        RedirectMode::Listen(timeout) => Ok(Some((bind_redirect_listener(redirect_url).await?, *timeout))),
+        RedirectMode::Manual => {
+            if redirect_url.port() == Some(0) {
+                bind_redirect_listener(redirect_url).await?;
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Bind the local port in the redirect URL. Port 0 lets the OS pick a free
+/// port, which is then written back into the redirect URL.
+async fn bind_redirect_listener(redirect_url: &mut Url) -> Result<TcpListener, LibError> {
+    let port = match redirect_url.port() {
+        Some(port) => port,
+        None => {
+            return Err(LibError::NoRedirectPortError);
         }
+    };
 
-        let message = "Go back to your terminal :)";
-        let response = format!(
-            "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{}",
-            message.len(),
-            message
-        );
-        stream.write_all(response.as_bytes()).unwrap();
+    let address = match redirect_url.host() {
+        Some(Host::Ipv6(address)) => IpAddr::V6(address),
+        _ => IpAddr::V4(Ipv4Addr::LOCALHOST),
+    };
+
+    let listener = TcpListener::bind((address, port)).await?;
 
-        if state.secret() != csrf_state.secret() {
-            return Err(LibError::TokenCsrfError);
+    if port == 0 {
+        let port = listener.local_addr()?.port();
+        redirect_url
+            .set_port(Some(port))
+            .map_err(|_| LibError::NoRedirectPortError)?;
+    }
+
+    Ok(listener)
+}
+
+/// Compare the state of the redirect with the state of the authorize request.
+/// When only the code was pasted there is no state to compare with.
+fn check_state(state: Option<&CsrfToken>, csrf_state: &CsrfToken) -> Result<(), LibError> {
+    match state {
+        Some(state) if state.secret() != csrf_state.secret() => Err(LibError::TokenCsrfError),
+        _ => Ok(()),
+    }
+}
+
+/// Read the redirect URL, or only the authorization code, from stdin.
+fn read_pasted_redirect() -> Result<(AuthorizationCode, Option<CsrfToken>), LibError> {
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    parse_pasted_redirect(line.trim())
+}
+
+fn parse_pasted_redirect(input: &str) -> Result<(AuthorizationCode, Option<CsrfToken>), LibError> {
+    if input.is_empty() {
+        return Err(LibError::NoAuthorizationCode);
+    }
+
+    // Anything that looks like a query string is treated as the redirect, also
+    // when only the path (e.g. "/?state=...&code=...") was copied.
+    if !input.contains('=') {
+        return Ok((AuthorizationCode::new(input.to_string()), None));
+    }
+
+    let url = match Url::parse(input) {
+        Ok(url) => url,
+        Err(_) => {
+            let path = if input.starts_with('/') || input.starts_with('?') {
+                input.to_string()
+            } else {
+                format!("/?{input}")
+            };
+            Url::parse(&("http://localhost".to_string() + &path))?
         }
+    };
 
-        // Exchange the code with a token.
-        let token_response = client
-            .exchange_code(code)
-            .map_err(|openid_err| LibError::OpenIdError(openid_err.to_string()))?
-            .set_pkce_verifier(pkce_verifier)
-            .request_async(&http_client)
-            .await
-            .map_err(|e| {
-                eprintln!("{:?}", e);
-                LibError::OpenIdError("Failed to access token endpoint".to_string())
-            })?;
-
-        let access_token_expires = match token_response.expires_in() {
-            None => 0,
-            Some(expires_in) => now + expires_in.as_secs(),
-        };
-
-        let id_token_verifier: CoreIdTokenVerifier = client.id_token_verifier();
-        let id_token_claims: &CoreIdTokenClaims = token_response
-            .extra_fields()
-            .id_token()
-            .ok_or(LibError::NoIdToken)?
-            .claims(&id_token_verifier, &nonce)
-            .map_err(|_| LibError::OpenIdError("Failed to verify ID token".to_string()))?;
-
-        let id_token = token_response
-            .id_token()
-            .ok_or(LibError::NoIdToken)?
-            .to_string();
-        let refresh_token = token_response
-            .refresh_token()
-            .ok_or(LibError::NoRefreshToken)?;
-        let access_token = token_response.access_token().secret().to_string();
-
-        let scopes = token_response.scopes().ok_or(LibError::NoScopes)?;
-
-        config.scopes = scopes.iter().map(|scope| scope.to_string()).collect();
-        config.refresh_token = Some(refresh_token.secret().to_string());
-        config.id_token = Some(Token::new(
-            id_token,
-            id_token_claims.expiration().timestamp() as u64,
+    match redirect_result(&url) {
+        Some(Ok((code, state))) => Ok((code, Some(state))),
+        Some(Err(err)) => Err(err),
+        None => Err(LibError::NoAuthorizationCode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(input: &str) -> (String, Option<String>) {
+        let (code, state) = parse_pasted_redirect(input).unwrap();
+        (code.secret().clone(), state.map(|state| state.secret().clone()))
+    }
+
+    #[test]
+    fn parses_full_redirect_urls() {
+        assert_eq!(
+            parsed("http://localhost:8080/?state=abc&code=4%2F0Ab&scope=openid"),
+            ("4/0Ab".to_string(), Some("abc".to_string()))
+        );
+    }
+
+    #[test]
+    fn parses_bare_query_strings_and_paths() {
+        let expected = ("the-code".to_string(), Some("abc".to_string()));
+        assert_eq!(parsed("state=abc&code=the-code"), expected);
+        assert_eq!(parsed("?state=abc&code=the-code"), expected);
+        assert_eq!(parsed("/callback?state=abc&code=the-code"), expected);
+    }
+
+    #[test]
+    fn parses_bare_codes() {
+        assert_eq!(parsed("4/0AbCdEf"), ("4/0AbCdEf".to_string(), None));
+        assert!(matches!(parse_pasted_redirect(""), Err(LibError::NoAuthorizationCode)));
+    }
+
+    #[test]
+    fn rejects_redirects_without_a_code_or_state() {
+        assert!(matches!(parse_pasted_redirect("state=abc&scope=openid"), Err(LibError::NoAuthorizationCode)));
+        assert!(matches!(parse_pasted_redirect("code=the-code"), Err(LibError::TokenCsrfError)));
+    }
+
+    #[test]
+    fn rejects_error_responses() {
+        assert!(matches!(
+            parse_pasted_redirect("http://localhost:8080/?error=access_denied&state=abc"),
+            Err(LibError::AccessDenied(_))
+        ));
+        assert!(matches!(
+            parse_pasted_redirect("error=invalid_scope&error_description=Bad+scope"),
+            Err(LibError::AuthorizationError(description)) if description == "invalid_scope: Bad scope"
         ));
-        config.access_token = Some(Token::new(access_token, access_token_expires));
+    }
+
+    #[tokio::test]
+    async fn binds_a_free_port_for_port_0() {
+        let mut redirect_url = Url::parse("http://127.0.0.1:0/callback").unwrap();
+        let listener = bind_redirect_listener(&mut redirect_url).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert_ne!(port, 0);
+        assert_eq!(redirect_url.as_str(), format!("http://127.0.0.1:{}/callback", port));
+
+        let mut redirect_url = Url::parse("http://[::1]:0/").unwrap();
+        if let Ok(listener) = bind_redirect_listener(&mut redirect_url).await {
+            assert!(listener.local_addr().unwrap().is_ipv6());
+            assert_eq!(redirect_url.port(), Some(listener.local_addr().unwrap().port()));
+        }
+    }
+
+    #[tokio::test]
+    async fn binds_the_port_of_the_redirect_url() {
+        let port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+        let mut redirect_url = Url::parse(&format!("http://localhost:{}/", port)).unwrap();
+        let listener = bind_redirect_listener(&mut redirect_url).await.unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), port);
+        assert_eq!(redirect_url.port(), Some(port));
 
-        return config.save_config(config_base_path);
+        let mut redirect_url = Url::parse("https://example.com/callback").unwrap();
+        assert!(matches!(bind_redirect_listener(&mut redirect_url).await, Err(LibError::NoRedirectPortError)));
+    }
+
+    #[tokio::test]
+    async fn picks_a_port_for_manual_logins_with_port_0() {
+        let mut redirect_url = Url::parse("http://127.0.0.1:0/").unwrap();
+        assert!(redirect_listener(&RedirectMode::Manual, &mut redirect_url).await.unwrap().is_none());
+        assert!(!matches!(redirect_url.port(), None | Some(0)), "{}", redirect_url);
+
+        // Manual logins do not need a local port.
+        for url in ["http://localhost:8080/", "https://example.com/callback"] {
+            let mut redirect_url = Url::parse(url).unwrap();
+            assert!(redirect_listener(&RedirectMode::Manual, &mut redirect_url).await.unwrap().is_none());
+            assert_eq!(redirect_url.as_str(), url);
+        }
     }
 
-    Err(LibError::NoResponse)
+    #[tokio::test]
+    async fn listens_on_a_free_port_with_port_0() {
+        let mut redirect_url = Url::parse("http://127.0.0.1:0/").unwrap();
+        let timeout = Duration::from_secs(1);
+        let (listener, listen_timeout) = redirect_listener(&RedirectMode::Listen(timeout), &mut redirect_url)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(listen_timeout, timeout);
+        assert_eq!(redirect_url.port(), Some(listener.local_addr().unwrap().port()));
+    }
+
+    #[test]
+    fn rejects_state_mismatches() {
+        let csrf_state = CsrfToken::new("abc".to_string());
+        let (_, state) = parse_pasted_redirect("state=other&code=the-code").unwrap();
+        assert!(matches!(check_state(state.as_ref(), &csrf_state), Err(LibError::TokenCsrfError)));
+
+        let (_, state) = parse_pasted_redirect("state=abc&code=the-code").unwrap();
+        assert!(check_state(state.as_ref(), &csrf_state).is_ok());
+        assert!(check_state(None, &csrf_state).is_ok());
+    }
 }
@@ -0,0 +1,152 @@
+//! An OpenID Connect provider on a local port, for the tests of the flows.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use base64::Engine;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::traits::PublicKeyParts;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::service_account::ServiceAccountKey;
+
+/// The client id that the ID tokens of the provider are issued to.
+pub(crate) const CLIENT_ID: &str = "client-id";
+
+/// The form of a request to an endpoint.
+pub(crate) type Form = HashMap<String, String>;
+
+/// The requests to the endpoints of a provider, as their paths and forms.
+pub(crate) type Requests = Arc<Mutex<Vec<(String, Form)>>>;
+
+/// Serve a provider on a local port. The discovery document and the keys are served by the provider,
+/// and the other endpoints (`/device`, `/token` and `/revoke`) answer with the status and body that
+/// `respond` returns for the issuer URL, the path and the form. Returns the issuer URL, and the requests
+/// to the other endpoints.
+pub(crate) async fn mock_provider<F>(respond: F) -> (String, Requests)
+where
+    F: Fn(&str, &str, &Form) -> (u16, Value) + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let issuer = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+
+    let received = requests.clone();
+    let server_issuer = issuer.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (path, form) = read_request(&mut stream).await;
+            let (status, body) = match path.as_str() {
+                "/.well-known/openid-configuration" => (200, discovery_document(&server_issuer)),
+                "/jwks" => (200, jwks()),
+                _ => {
+                    let response = respond(&server_issuer, &path, &form);
+                    received.lock().unwrap().push((path, form));
+                    response
+                }
+            };
+
+            let body = body.to_string();
+            let response = format!(
+                "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            stream.shutdown().await.unwrap();
+        }
+    });
+
+    (issuer, requests)
+}
+
+fn discovery_document(issuer: &str) -> Value {
+    json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/auth", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+        "device_authorization_endpoint": format!("{}/device", issuer),
+        "revocation_endpoint": format!("{}/revoke", issuer),
+        "jwks_uri": format!("{}/jwks", issuer),
+        "response_types_supported": ["code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["RS256"],
+    })
+}
+
+/// The fixture service account key, which the provider signs its ID tokens with.
+fn signing_key() -> (Value, ServiceAccountKey) {
+    let json = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/service-account.json"))
+        .unwrap();
+    (serde_json::from_str(&json).unwrap(), ServiceAccountKey::from_json(&json).unwrap())
+}
+
+/// The JSON Web Key Set with the public key of [signing_key].
+fn jwks() -> Value {
+    let (key, _) = signing_key();
+    let private_key = RsaPrivateKey::from_pkcs8_pem(key["private_key"].as_str().unwrap()).unwrap();
+    let public_key = RsaPublicKey::from(&private_key);
+    json!({"keys": [{
+        "kty": "RSA",
+        "alg": "RS256",
+        "use": "sig",
+        "kid": key["private_key_id"],
+        "n": BASE64_URL.encode(public_key.n().to_bytes_be()),
+        "e": BASE64_URL.encode(public_key.e().to_bytes_be()),
+    }]})
+}
+
+/// A token response of the provider, with an ID token for [CLIENT_ID] that expires in an hour.
+pub(crate) fn issue_tokens(issuer: &str) -> Value {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let id_token = signing_key()
+        .1
+        .sign_jwt(&json!({
+            "iss": issuer,
+            "aud": CLIENT_ID,
+            "sub": "12345",
+            "iat": now,
+            "exp": now + 3600,
+        }))
+        .unwrap();
+    json!({
+        "access_token": "access-token",
+        "token_type": "Bearer",
+        "expires_in": 3599,
+        "refresh_token": "refresh-token",
+        "id_token": id_token,
+    })
+}
+
+/// Read a request, and return its path and form.
+async fn read_request(stream: &mut TcpStream) -> (String, Form) {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        let read = stream.read(&mut buffer).await.unwrap();
+        assert!(read > 0, "incomplete request");
+        request.extend_from_slice(&buffer[..read]);
+
+        let Some(header_end) = request.windows(4).position(|window| window == b"\r\n\r\n") else {
+            continue;
+        };
+        let headers = String::from_utf8_lossy(&request[..header_end]).to_lowercase();
+        let path = headers.split_whitespace().nth(1).unwrap().to_string();
+        let content_length: usize = headers
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .map_or(0, |length| length.trim().parse().unwrap());
+        let body = &request[header_end + 4..];
+        if body.len() >= content_length {
+            let form = url::form_urlencoded::parse(&body[..content_length]).into_owned().collect();
+            return (path, form);
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::fmt;
+use std::ops::Deref;
+use std::path::Path;
+
+use crate::config_file::TOKEN_CACHE_DIR;
+use crate::config_toml::CONFIG_TOML;
+use crate::errors::LibError;
+
+/// The name of a profile, which is safe to use as a file name in the configuration directory.
+///
+/// It can not be empty, be [CONFIG_TOML] or [TOKEN_CACHE_DIR], start with a dot (which also
+/// rules out `.` and `..`), or contain path separators or control characters.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ProfileName(String);
+
+impl ProfileName {
+    pub fn new(name: &str) -> Result<ProfileName, LibError> {
+        let is_valid = !name.is_empty()
+            && name != CONFIG_TOML
+            && name != TOKEN_CACHE_DIR
+            && !name.starts_with('.')
+            && !name.chars().any(|c| c == '/' || c == '\\' || c.is_control());
+        if !is_valid {
+            return Err(LibError::InvalidProfileName(name.to_string()));
+        }
+        Ok(ProfileName(name.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for ProfileName {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for ProfileName {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl fmt::Display for ProfileName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
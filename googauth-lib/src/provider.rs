@@ -0,0 +1,96 @@
+use crate::config_file::{ConfigFile, Token};
+use crate::credential_store::CredentialStore;
+use crate::errors::LibError;
+use openidconnect::core::{
+    CoreAuthDisplay, CoreClaimName, CoreClaimType, CoreClientAuthMethod, CoreGrantType,
+    CoreIdTokenClaims, CoreJsonWebKey, CoreJweContentEncryptionAlgorithm,
+    CoreJweKeyManagementAlgorithm, CoreResponseMode, CoreResponseType, CoreSubjectIdentifierType,
+    CoreTokenResponse,
+};
+use openidconnect::{
+    reqwest, AdditionalProviderMetadata, IssuerUrl, OAuth2TokenResponse, ProviderMetadata,
+    TokenResponse,
+};
+
+/// The discovery document of a provider, with the additional metadata `A` that a flow needs,
+/// e.g. an endpoint that is not part of the core metadata.
+pub(crate) type ProviderMetadataWith<A> = ProviderMetadata<
+    A,
+    CoreAuthDisplay,
+    CoreClientAuthMethod,
+    CoreClaimName,
+    CoreClaimType,
+    CoreGrantType,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJweKeyManagementAlgorithm,
+    CoreJsonWebKey,
+    CoreResponseMode,
+    CoreResponseType,
+    CoreSubjectIdentifierType,
+>;
+
+/// The HTTP client of all requests to the provider.
+pub(crate) fn http_client() -> Result<reqwest::Client, LibError> {
+    reqwest::ClientBuilder::new()
+        // Following redirects opens the client up to SSRF vulnerabilities.
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .map_err(|openid_error| LibError::OpenIdError(openid_error.to_string()))
+}
+
+/// Fetch the OpenID Connect discovery document of the issuer of the configuration.
+pub(crate) async fn discover<A: AdditionalProviderMetadata>(
+    config: &ConfigFile,
+    http_client: &reqwest::Client,
+) -> Result<ProviderMetadataWith<A>, LibError> {
+    let issuer_url = IssuerUrl::new(config.issuer_url.to_string())?;
+    ProviderMetadataWith::<A>::discover_async(issuer_url, http_client)
+        .await
+        .map_err(|_| LibError::OpenIdError("Failed to discover OpenID Provider".to_string()))
+}
+
+/// The assumed lifetime of an access token that the provider returns without an expiry,
+/// since an expiry of 0 never expires, see [Token::expires_within].
+const DEFAULT_ACCESS_TOKEN_LIFETIME_SECS: u64 = 3600;
+
+/// The expiry time of the access token of a token response received at `now`.
+pub(crate) fn access_token_expiry<TR: OAuth2TokenResponse>(token_response: &TR, now: u64) -> u64 {
+    let expires_in = token_response
+        .expires_in()
+        .map_or(DEFAULT_ACCESS_TOKEN_LIFETIME_SECS, |expires_in| expires_in.as_secs());
+    now + expires_in
+}
+
+/// Save the tokens of a login, given the verified claims of its ID token.
+/// A login must return a refresh token, so that the other tokens can be refreshed later.
+pub(crate) fn save_login_tokens<S: CredentialStore + ?Sized>(
+    config: &mut ConfigFile,
+    store: &S,
+    token_response: &CoreTokenResponse,
+    id_token_claims: &CoreIdTokenClaims,
+    now: u64,
+) -> Result<(), LibError> {
+    let access_token_expires = access_token_expiry(token_response, now);
+
+    let id_token = token_response
+        .id_token()
+        .ok_or(LibError::NoIdToken)?
+        .to_string();
+    let refresh_token = token_response
+        .refresh_token()
+        .ok_or(LibError::NoRefreshToken)?;
+    let access_token = token_response.access_token().secret().to_string();
+
+    // Providers may leave out the scopes when they are identical to the requested ones.
+    if let Some(scopes) = token_response.scopes() {
+        config.scopes = scopes.iter().map(|scope| scope.to_string()).collect();
+    }
+    config.refresh_token = Some(refresh_token.secret().to_string());
+    config.id_token = Some(Token::new(
+        id_token,
+        id_token_claims.expiration().timestamp() as u64,
+    ));
+    config.access_token = Some(Token::new(access_token, access_token_expires));
+
+    store.save(config)
+}
@@ -0,0 +1,270 @@
+use std::time::Duration;
+
+use crate::errors::LibError;
+use openidconnect::{AuthorizationCode, CsrfToken};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use url::Url;
+
+/// How long to wait for the browser to be redirected back, unless told otherwise.
+pub const DEFAULT_REDIRECT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How long a single connection may take to send its request line. Browsers
+/// open speculative connections that never send anything, and those must
+/// not block the real redirect.
+const REQUEST_LINE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait before accepting connections again after an error, e.g. when
+/// there are too many open files.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Longer request lines than this are not a redirect from the provider.
+const MAX_REQUEST_LINE_LENGTH: u64 = 16 * 1024;
+
+/// Listen on the local port until the browser is redirected to `path`
+/// with either an authorization code or an error, or until `timeout` passes.
+///
+/// Requests for other paths, such as `/favicon.ico`, and malformed requests
+/// are answered and ignored.
+pub(crate) async fn receive_redirect(
+    listener: TcpListener,
+    path: &str,
+    timeout: Duration,
+) -> Result<(AuthorizationCode, CsrfToken), LibError> {
+    tokio::time::timeout(timeout, accept_redirect(listener, path))
+        .await
+        .map_err(|_| LibError::RedirectTimeout)?
+}
+
+async fn accept_redirect(
+    listener: TcpListener,
+    path: &str,
+) -> Result<(AuthorizationCode, CsrfToken), LibError> {
+    // Each connection is answered in its own task, so that a speculative connection
+    // which never sends a request does not hold up the redirect.
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((mut stream, _)) => {
+                    let path = path.to_string();
+                    connections.spawn(async move { handle_connection(&mut stream, &path).await });
+                }
+                Err(_) => tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await,
+            },
+            Some(handled) = connections.join_next() => {
+                if let Ok(Some(result)) = handled {
+                    return result;
+                }
+            }
+        }
+    }
+}
+
+/// Answer a single request. Returns `None` if the request was not the redirect.
+async fn handle_connection(
+    stream: &mut TcpStream,
+    path: &str,
+) -> Option<Result<(AuthorizationCode, CsrfToken), LibError>> {
+    let request_line = match tokio::time::timeout(REQUEST_LINE_TIMEOUT, read_request_line(stream))
+        .await
+    {
+        Ok(Some(request_line)) => request_line,
+        _ => {
+            respond(stream, "400 Bad Request", "Bad request").await;
+            return None;
+        }
+    };
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => {
+            respond(stream, "400 Bad Request", "Bad request").await;
+            return None;
+        }
+    };
+
+    let url = match Url::parse("http://localhost").and_then(|base| base.join(target)) {
+        Ok(url) => url,
+        Err(_) => {
+            respond(stream, "400 Bad Request", "Bad request").await;
+            return None;
+        }
+    };
+
+    if method != "GET" || url.path() != path {
+        respond(stream, "404 Not Found", "Not found").await;
+        return None;
+    }
+
+    match redirect_result(&url) {
+        Some(Ok((code, state))) => {
+            respond(stream, "200 OK", "Go back to your terminal :)").await;
+            Some(Ok((code, state)))
+        }
+        Some(Err(err)) => {
+            respond(stream, "200 OK", "Sign in failed, go back to your terminal for details").await;
+            Some(Err(err))
+        }
+        None => {
+            respond(stream, "400 Bad Request", "No authorization code in the request").await;
+            None
+        }
+    }
+}
+
+async fn read_request_line(stream: &mut TcpStream) -> Option<String> {
+    let mut reader = BufReader::new(stream).take(MAX_REQUEST_LINE_LENGTH);
+    let mut request_line = String::new();
+    match reader.read_line(&mut request_line).await {
+        Ok(_) if request_line.ends_with('\n') => Some(request_line),
+        _ => None,
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, message: &str) {
+    let response = format!(
+        "HTTP/1.1 {}\r\ncontent-type: text/plain; charset=utf-8\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        status,
+        message.len(),
+        message
+    );
+    // The browser may already have closed the connection, which does not matter.
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Read the authorization code and state, or the error, from a redirect URL.
+///
+/// Returns `None` if the URL holds neither a code nor an error.
+pub(crate) fn redirect_result(
+    url: &Url,
+) -> Option<Result<(AuthorizationCode, CsrfToken), LibError>> {
+    let query_value = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+
+    if let Some(error) = query_value("error") {
+        let description = match query_value("error_description") {
+            Some(description) => format!("{error}: {description}"),
+            None => error.clone(),
+        };
+        return Some(Err(match error.as_str() {
+            "access_denied" => LibError::AccessDenied(description),
+            _ => LibError::AuthorizationError(description),
+        }));
+    }
+
+    let code = query_value("code")?;
+    let state = match query_value("state") {
+        Some(state) => state,
+        None => return Some(Err(LibError::TokenCsrfError)),
+    };
+
+    Some(Ok((AuthorizationCode::new(code), CsrfToken::new(state))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send the request to [handle_connection], and return its result and the response.
+    async fn handle(request: &[u8]) -> (Option<Result<(AuthorizationCode, CsrfToken), LibError>>, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        client.write_all(request).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let result = handle_connection(&mut stream, "/callback").await;
+        drop(stream);
+        let mut response = String::new();
+        // The connection is reset if the request was not read to the end.
+        let _ = client.read_to_string(&mut response).await;
+        (result, response)
+    }
+
+    #[tokio::test]
+    async fn receives_the_code_and_state() {
+        let (result, response) = handle(b"GET /callback?state=abc&code=the-code HTTP/1.1\r\n\r\n").await;
+        let (code, state) = result.unwrap().unwrap();
+        assert_eq!(code.secret(), "the-code");
+        assert_eq!(state.secret(), "abc");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[tokio::test]
+    async fn ignores_empty_requests() {
+        let (result, response) = handle(b"").await;
+        assert!(result.is_none());
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+    }
+
+    #[tokio::test]
+    async fn ignores_requests_without_a_path() {
+        let (result, response) = handle(b"GET\r\n\r\n").await;
+        assert!(result.is_none());
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+    }
+
+    #[tokio::test]
+    async fn ignores_other_paths() {
+        let (result, response) = handle(b"GET /favicon.ico HTTP/1.1\r\n\r\n").await;
+        assert!(result.is_none());
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+    }
+
+    #[tokio::test]
+    async fn ignores_requests_that_are_not_utf8() {
+        let (result, response) = handle(b"GET /callback?code=\xff\xfe HTTP/1.1\r\n\r\n").await;
+        assert!(result.is_none());
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+    }
+
+    #[tokio::test]
+    async fn ignores_oversized_requests() {
+        let mut request = b"GET /callback?state=abc&code=".to_vec();
+        request.resize(MAX_REQUEST_LINE_LENGTH as usize + 1024, b'a');
+        request.extend_from_slice(b" HTTP/1.1\r\n\r\n");
+        let (result, _) = handle(&request).await;
+        assert!(result.is_none());
+    }
+
+    #[tokio::test]
+    async fn handles_a_missing_code_or_state() {
+        let (result, response) = handle(b"GET /callback?state=abc HTTP/1.1\r\n\r\n").await;
+        assert!(result.is_none());
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+
+        let (result, _) = handle(b"GET /callback?code=the-code HTTP/1.1\r\n\r\n").await;
+        assert!(matches!(result, Some(Err(LibError::TokenCsrfError))));
+    }
+
+    #[tokio::test]
+    async fn does_not_wait_for_connections_without_a_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let receiving = tokio::spawn(receive_redirect(listener, "/callback", REQUEST_LINE_TIMEOUT / 2));
+
+        let _speculative = TcpStream::connect(address).await.unwrap();
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(b"GET /callback?state=abc&code=the-code HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+
+        let (code, _) = receiving.await.unwrap().unwrap();
+        assert_eq!(code.secret(), "the-code");
+    }
+
+    #[tokio::test]
+    async fn returns_error_responses() {
+        let (result, response) = handle(b"GET /callback?error=access_denied&state=abc HTTP/1.1\r\n\r\n").await;
+        assert!(matches!(result, Some(Err(LibError::AccessDenied(_)))));
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+    }
+}
@@ -1,30 +1,22 @@
-use crate::config_file::{ConfigBasePath, ConfigFile, Token};
+use crate::config_file::{ConfigFile, Token};
+use crate::credential_store::CredentialStore;
 use crate::errors::LibError;
-use openidconnect::core::{CoreClient, CoreIdTokenVerifier, CoreProviderMetadata};
+use crate::provider::{access_token_expiry, discover, http_client};
+use openidconnect::core::{CoreClient, CoreIdTokenVerifier};
 use openidconnect::{
-    reqwest, ClientId, ClientSecret, IssuerUrl, OAuth2TokenResponse, RefreshToken, Scope,
-    TokenResponse,
+    ClientId, ClientSecret, EmptyAdditionalProviderMetadata, OAuth2TokenResponse, RefreshToken,
+    Scope, TokenResponse,
 };
 use std::time::{SystemTime, UNIX_EPOCH};
 
-pub async fn refresh_google_login(
+pub async fn refresh_google_login<S: CredentialStore + ?Sized>(
     config: &mut ConfigFile,
-    config_base_path: &ConfigBasePath,
+    store: &S,
 ) -> Result<(), LibError> {
     let google_client_id = ClientId::new(config.client_id.to_string());
     let google_client_secret = ClientSecret::new(config.client_secret.to_string());
-    let issuer_url =
-        IssuerUrl::new("https://accounts.google.com".to_string()).expect("Invalid issuer URL");
-    let http_client = reqwest::ClientBuilder::new()
-        // Following redirects opens the client up to SSRF vulnerabilities.
-        .redirect(reqwest::redirect::Policy::none())
-        .build()
-        .map_err(|openid_error| LibError::OpenIdError(openid_error.to_string()))?;
-
-    // Fetch Google's OpenID Connect discovery document.
-    let provider_metadata = CoreProviderMetadata::discover_async(issuer_url, &http_client)
-        .await
-        .map_err(|_| LibError::OpenIdError("Failed to discover OpenID Provider".to_string()))?;
+    let http_client = http_client()?;
+    let provider_metadata = discover::<EmptyAdditionalProviderMetadata>(config, &http_client).await?;
 
     let refresh_token = match &config.refresh_token {
         Some(refresh_token) => RefreshToken::new(refresh_token.to_string()),
@@ -62,23 +54,16 @@ pub async fn refresh_google_login(
     };
 
     let access_token = token_response.access_token().secret().to_string();
-    let access_token_exp = match token_response.expires_in() {
-        None => 0,
-        Some(expires_in) => now + expires_in.as_secs(),
-    };
+    let access_token_exp = access_token_expiry(&token_response, now);
     config.access_token = Some(Token::new(access_token, access_token_exp));
 
     let id_token = token_response.id_token().ok_or(LibError::NoIdToken)?;
     let id_token_verifier: CoreIdTokenVerifier = client.id_token_verifier();
-    let id_token_claims = match id_token.claims(&id_token_verifier, |_: Option<&_>| Ok(())) {
-        Ok(claims) => claims,
-        Err(e) => {
-            println!("ERR {:?}", e);
-            return Err(LibError::CouldNotReadClaims);
-        }
-    };
+    let id_token_claims = id_token
+        .claims(&id_token_verifier, |_: Option<&_>| Ok(()))
+        .map_err(|e| LibError::CouldNotReadClaims(e.to_string()))?;
     let id_token_exp = id_token_claims.expiration().timestamp() as u64;
     config.id_token = Some(Token::new(id_token.to_string(), id_token_exp));
 
-    config.save_config(config_base_path)
+    store.save(config)
 }
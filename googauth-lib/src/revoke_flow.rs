@@ -0,0 +1,196 @@
+use crate::config_file::ConfigFile;
+use crate::credential_store::CredentialStore;
+use crate::errors::LibError;
+use crate::provider::{discover, http_client};
+use openidconnect::core::{
+    CoreClient, CoreErrorResponseType, CoreRevocableToken, CoreRevocationErrorResponse,
+};
+use openidconnect::{
+    AccessToken, AdditionalProviderMetadata, ClientId, ClientSecret, RefreshToken,
+    RequestTokenError, RevocationErrorResponseType, RevocationUrl,
+};
+use serde::{Deserialize, Serialize};
+
+/// The revocation endpoint is not part of the core discovery
+/// metadata, so it has to be read as additional metadata.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct RevocationEndpointProviderMetadata {
+    revocation_endpoint: Option<RevocationUrl>,
+}
+
+impl AdditionalProviderMetadata for RevocationEndpointProviderMetadata {}
+
+/// Revoke the refresh and access tokens of a configuration at the provider's
+/// revocation endpoint, and remove all tokens from the saved configuration.
+pub async fn revoke_google_login<S: CredentialStore + ?Sized>(
+    config: &mut ConfigFile,
+    store: &S,
+) -> Result<(), LibError> {
+    let google_client_id = ClientId::new(config.client_id.to_string());
+    let google_client_secret = ClientSecret::new(config.client_secret.to_string());
+    let http_client = http_client()?;
+    let provider_metadata = discover::<RevocationEndpointProviderMetadata>(config, &http_client).await?;
+
+    let revocation_endpoint = provider_metadata
+        .additional_metadata()
+        .revocation_endpoint
+        .clone()
+        .ok_or(LibError::NoRevocationEndpoint)?;
+
+    let client = CoreClient::from_provider_metadata(
+        provider_metadata,
+        google_client_id,
+        Some(google_client_secret),
+    )
+    .set_revocation_url(revocation_endpoint);
+
+    if let Some(refresh_token) = &config.refresh_token {
+        let token = CoreRevocableToken::RefreshToken(RefreshToken::new(refresh_token.to_string()));
+        let result = client
+            .revoke_token(token)
+            .map_err(|openid_err| LibError::OpenIdError(openid_err.to_string()))?
+            .request_async(&http_client)
+            .await;
+        revocation_result(result)?;
+    }
+
+    if let Some(access_token) = &config.access_token {
+        let token =
+            CoreRevocableToken::AccessToken(AccessToken::new(access_token.secret.to_string()));
+        let result = client
+            .revoke_token(token)
+            .map_err(|openid_err| LibError::OpenIdError(openid_err.to_string()))?
+            .request_async(&http_client)
+            .await;
+        revocation_result(result)?;
+    }
+
+    forget_tokens(config, store)
+}
+
+/// Remove all tokens from the configuration, and save it.
+fn forget_tokens<S: CredentialStore + ?Sized>(config: &mut ConfigFile, store: &S) -> Result<(), LibError> {
+    config.refresh_token = None;
+    config.id_token = None;
+    config.access_token = None;
+    config.subject_access_tokens.clear();
+
+    store.save(config)
+}
+
+fn revocation_result<RE: std::error::Error + 'static>(
+    result: Result<(), RequestTokenError<RE, CoreRevocationErrorResponse>>,
+) -> Result<(), LibError> {
+    match result {
+        Ok(()) => Ok(()),
+        // Tokens that have expired, or were already revoked, are rejected by
+        // some providers (e.g. Google) instead of being ignored as RFC 7009 says.
+        Err(RequestTokenError::ServerResponse(response))
+            if matches!(
+                response.error(),
+                RevocationErrorResponseType::Basic(CoreErrorResponseType::Extension(error))
+                    if error == "invalid_token"
+            ) =>
+        {
+            Ok(())
+        }
+        Err(e) => Err(LibError::CouldNotRevokeToken(e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_file::{Token, GOOGLE_ISSUER_URL};
+    use crate::credential_store::MemoryStore;
+    use crate::mock_provider::{mock_provider, CLIENT_ID};
+    use openidconnect::core::CoreRevocationErrorResponse;
+    use serde_json::json;
+
+    fn server_response(
+        error: RevocationErrorResponseType,
+    ) -> Result<(), RequestTokenError<std::io::Error, CoreRevocationErrorResponse>> {
+        Err(RequestTokenError::ServerResponse(CoreRevocationErrorResponse::new(error, None, None)))
+    }
+
+    fn invalid_token() -> RevocationErrorResponseType {
+        RevocationErrorResponseType::Basic(CoreErrorResponseType::Extension("invalid_token".to_string()))
+    }
+
+    #[test]
+    fn ignores_invalid_tokens() {
+        assert!(revocation_result::<std::io::Error>(Ok(())).is_ok());
+        assert!(revocation_result(server_response(invalid_token())).is_ok());
+    }
+
+    #[test]
+    fn reports_other_errors() {
+        for error in [
+            RevocationErrorResponseType::UnsupportedTokenType,
+            RevocationErrorResponseType::Basic(CoreErrorResponseType::InvalidClient),
+            RevocationErrorResponseType::Basic(CoreErrorResponseType::Extension("server_error".to_string())),
+        ] {
+            assert!(matches!(revocation_result(server_response(error)), Err(LibError::CouldNotRevokeToken(_))));
+        }
+        let request_error = RequestTokenError::Request(std::io::Error::other("connection refused"));
+        assert!(matches!(revocation_result(Err(request_error)), Err(LibError::CouldNotRevokeToken(_))));
+    }
+
+    fn signed_in_config(issuer: String) -> ConfigFile {
+        let mut config = ConfigFile::new("myprofile", CLIENT_ID, "client-secret", &["openid".to_string()], "");
+        config.issuer_url = issuer;
+        config.refresh_token = Some("refresh-token".to_string());
+        config.id_token = Some(Token::new("id-token".to_string(), u64::MAX));
+        config.access_token = Some(Token::new("access-token".to_string(), u64::MAX));
+        let subject_token = Token::new("subject-token".to_string(), u64::MAX);
+        config.subject_access_tokens.insert("alice@example.com".to_string(), subject_token);
+        config
+    }
+
+    fn assert_no_tokens(config: &ConfigFile) {
+        assert!(config.refresh_token.is_none());
+        assert!(config.id_token.is_none());
+        assert!(config.access_token.is_none());
+        assert!(config.subject_access_tokens.is_empty());
+    }
+
+    #[test]
+    fn forgets_all_tokens() {
+        let store = MemoryStore::new();
+        let mut config = signed_in_config(GOOGLE_ISSUER_URL.to_string());
+        store.save(&config).unwrap();
+
+        forget_tokens(&mut config, &store).unwrap();
+        assert_no_tokens(&config);
+        assert_no_tokens(&store.load("myprofile").unwrap());
+    }
+
+    #[tokio::test]
+    async fn clears_the_tokens_that_can_not_be_revoked() {
+        let (issuer, requests) = mock_provider(|_, _, _| (200, json!({}))).await;
+        let store = MemoryStore::new();
+        let mut config = signed_in_config(issuer);
+        config.refresh_token = None;
+        config.access_token = None;
+        store.save(&config).unwrap();
+
+        revoke_google_login(&mut config, &store).await.unwrap();
+        assert!(requests.lock().unwrap().is_empty());
+        assert_no_tokens(&store.load("myprofile").unwrap());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_tokens_if_they_could_not_be_revoked() {
+        // The revocation endpoint of the provider is not HTTPS, which is rejected before any request.
+        let (issuer, requests) = mock_provider(|_, _, _| (200, json!({}))).await;
+        let store = MemoryStore::new();
+        let mut config = signed_in_config(issuer);
+        store.save(&config).unwrap();
+
+        assert!(revoke_google_login(&mut config, &store).await.is_err());
+        assert!(requests.lock().unwrap().is_empty());
+        let saved = store.load("myprofile").unwrap();
+        assert_eq!(saved.refresh_token.as_deref(), Some("refresh-token"));
+        assert_eq!(saved.subject_access_tokens.len(), 1);
+    }
+}
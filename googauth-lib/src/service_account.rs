@@ -0,0 +1,96 @@
+use std::fs;
+use std::path::Path;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use base64::Engine;
+use rsa::pkcs1v15::SigningKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::sha2::Sha256;
+use rsa::signature::{SignatureEncoding, Signer};
+use rsa::RsaPrivateKey;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::config_file::ConfigFile;
+use crate::errors::LibError;
+
+/// The scope of a service account profile that is created without any scopes.
+pub const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+const SERVICE_ACCOUNT: &str = "service_account";
+
+/// A service account JSON key, as created in the Google Cloud console or with
+/// `gcloud iam service-accounts keys create`.
+#[derive(Deserialize)]
+pub(crate) struct ServiceAccountKey {
+    #[serde(rename = "type")]
+    key_type: String,
+    pub client_email: String,
+    private_key_id: String,
+    private_key: String,
+    pub token_uri: String,
+}
+
+impl ServiceAccountKey {
+    pub fn read(path: &Path) -> Result<ServiceAccountKey, LibError> {
+        let json = fs::read_to_string(path).map_err(|e| {
+            LibError::InvalidServiceAccountKey(format!("Could not read {}: {}", path.display(), e))
+        })?;
+        ServiceAccountKey::from_json(&json)
+    }
+
+    pub fn from_json(json: &str) -> Result<ServiceAccountKey, LibError> {
+        let key: ServiceAccountKey =
+            serde_json::from_str(json).map_err(|e| LibError::InvalidServiceAccountKey(e.to_string()))?;
+        if key.key_type != SERVICE_ACCOUNT {
+            return Err(LibError::InvalidServiceAccountKey(format!(
+                "The key is of type {}, not {}",
+                key.key_type, SERVICE_ACCOUNT
+            )));
+        }
+        key.signing_key()?;
+        Ok(key)
+    }
+
+    /// Sign the claims as a JWT with the private key of the service account.
+    pub fn sign_jwt(&self, claims: &Value) -> Result<String, LibError> {
+        let header = json!({ "alg": "RS256", "typ": "JWT", "kid": self.private_key_id });
+        let signing_input = format!(
+            "{}.{}",
+            BASE64_URL.encode(serde_json::to_vec(&header)?),
+            BASE64_URL.encode(serde_json::to_vec(claims)?)
+        );
+        let signature = self.signing_key()?.sign(signing_input.as_bytes());
+        Ok(format!("{}.{}", signing_input, BASE64_URL.encode(signature.to_bytes())))
+    }
+
+    fn signing_key(&self) -> Result<SigningKey<Sha256>, LibError> {
+        let private_key = RsaPrivateKey::from_pkcs8_pem(&self.private_key)
+            .map_err(|e| LibError::InvalidServiceAccountKey(format!("Invalid private key: {}", e)))?;
+        Ok(SigningKey::new(private_key))
+    }
+}
+
+/// The expiry time of a JWT, e.g. an ID token, read without verifying its signature.
+pub(crate) fn jwt_expiry(jwt: &str) -> Option<u64> {
+    let claims = BASE64_URL.decode(jwt.split('.').nth(1)?).ok()?;
+    serde_json::from_slice::<Value>(&claims).ok()?.get("exp")?.as_u64()
+}
+
+impl ConfigFile {
+    /// Create a configuration that gets its tokens with a service account key, instead of
+    /// signing in as a user. The key stays in its file, which is read whenever tokens are fetched.
+    pub fn from_service_account_key_file(name: &str, path: &Path, scopes: &[String]) -> Result<ConfigFile, LibError> {
+        let key = ServiceAccountKey::read(path)?;
+        let path = path.canonicalize()?;
+        let scopes = if scopes.is_empty() {
+            vec![CLOUD_PLATFORM_SCOPE.to_string()]
+        } else {
+            scopes.to_vec()
+        };
+
+        let mut config = ConfigFile::new(name, &key.client_email, "", &scopes, "");
+        config.service_account_key = Some(path.to_string_lossy().into_owned());
+        Ok(config)
+    }
+}
@@ -0,0 +1,184 @@
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use openidconnect::reqwest;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config_file::{ConfigFile, Token};
+use crate::credential_store::CredentialStore;
+use crate::errors::LibError;
+use crate::provider::http_client;
+use crate::service_account::{jwt_expiry, ServiceAccountKey};
+
+/// The grant type of RFC 7523, that exchanges a signed JWT assertion for tokens.
+const JWT_BEARER_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
+
+/// How long the assertion, or a self-signed access token, is valid. Google accepts at most an hour.
+/// Also the assumed lifetime of tokens that the token endpoint returns without an expiry.
+const ASSERTION_LIFETIME_SECS: u64 = 3600;
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    expires_in: Option<u64>,
+    id_token: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// Fetch the tokens of a service account configuration, see [ConfigFile::from_service_account_key_file],
+/// by exchanging assertions signed with its key at the token endpoint (RFC 7523).
+///
+/// The access token has the scopes of the configuration, and acts as the [ConfigFile::subject] if there is one.
+/// If the configuration has a target audience, an ID token for that audience is fetched as well. With [ConfigFile::self_signed_jwt] the access token
+/// is signed locally, and only the ID token needs the token endpoint.
+///
+/// This also refreshes the tokens, so the cached [ConfigFile::subject_access_tokens] are kept. Clear them
+/// before a login with another key or other settings, as their tokens were issued with the previous ones.
+pub async fn service_account_login<S: CredentialStore + ?Sized>(
+    config: &mut ConfigFile,
+    store: &S,
+) -> Result<(), LibError> {
+    let key_path = match &config.service_account_key {
+        Some(key_path) => key_path,
+        None => return Err(LibError::NotAServiceAccount(config.name.clone())),
+    };
+    let key = ServiceAccountKey::read(Path::new(key_path))?;
+    let http_client = http_client()?;
+    let now = now();
+
+    config.access_token = Some(if config.self_signed_jwt {
+        self_signed_access_token(config, &key, now)?
+    } else {
+        request_access_token(&http_client, &key, config, config.subject.as_deref(), now).await?
+    });
+
+    config.id_token = match &config.target_audience {
+        Some(target_audience) => {
+            let assertion = key.sign_jwt(&json!({
+                "iss": key.client_email,
+                "target_audience": target_audience,
+                "aud": key.token_uri,
+                "iat": now,
+                "exp": now + ASSERTION_LIFETIME_SECS,
+            }))?;
+            let response = request_token(&http_client, &key.token_uri, &assertion).await?;
+            let id_token = response.id_token.ok_or(LibError::NoIdToken)?;
+            // An expiry of 0 would never expire, so assume the usual lifetime if it can not be read.
+            let id_token_exp = jwt_expiry(&id_token).unwrap_or(now + ASSERTION_LIFETIME_SECS);
+            Some(Token::new(id_token, id_token_exp))
+        }
+        None => None,
+    };
+
+    store.save(config)
+}
+
+/// Fetch an access token of a service account configuration that acts as the `subject` user, with
+/// domain-wide delegation. The configuration is not changed, see [ConfigFile::subject_access_tokens].
+pub(crate) async fn service_account_access_token_as(config: &ConfigFile, subject: &str) -> Result<Token, LibError> {
+    let key_path = match &config.service_account_key {
+        Some(key_path) => key_path,
+        None => return Err(LibError::NotAServiceAccount(config.name.clone())),
+    };
+    if config.self_signed_jwt {
+        return Err(LibError::SelfSignedJwtSubject(config.name.clone()));
+    }
+    let key = ServiceAccountKey::read(Path::new(key_path))?;
+    request_access_token(&http_client()?, &key, config, Some(subject), now()).await
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Exchange an assertion for an access token with the scopes of the configuration, acting as the `subject`.
+async fn request_access_token(
+    http_client: &reqwest::Client,
+    key: &ServiceAccountKey,
+    config: &ConfigFile,
+    subject: Option<&str>,
+    now: u64,
+) -> Result<Token, LibError> {
+    let mut claims = json!({
+        "iss": key.client_email,
+        "scope": config.scopes.join(" "),
+        "aud": key.token_uri,
+        "iat": now,
+        "exp": now + ASSERTION_LIFETIME_SECS,
+    });
+    if let Some(subject) = subject {
+        claims["sub"] = subject.into();
+    }
+    let response = request_token(http_client, &key.token_uri, &key.sign_jwt(&claims)?).await?;
+    let access_token = response.access_token.ok_or(LibError::NoResponse)?;
+    // An expiry of 0 would never expire, so assume the usual lifetime if there is none.
+    let access_token_exp = now + response.expires_in.unwrap_or(ASSERTION_LIFETIME_SECS);
+    Ok(Token::new(access_token, access_token_exp))
+}
+
+/// An access token that is a JWT signed with the service account key, which Google APIs accept
+/// without a token endpoint round trip. The token is for the [ConfigFile::jwt_audience] if there is one,
+/// e.g. `https://pubsub.googleapis.com/`, and otherwise for the scopes of the configuration.
+fn self_signed_access_token(config: &ConfigFile, key: &ServiceAccountKey, now: u64) -> Result<Token, LibError> {
+    if config.subject.is_some() {
+        return Err(LibError::SelfSignedJwtSubject(config.name.clone()));
+    }
+    let exp = now + ASSERTION_LIFETIME_SECS;
+    let claims = match &config.jwt_audience {
+        Some(audience) => json!({
+            "iss": key.client_email,
+            "sub": key.client_email,
+            "aud": audience,
+            "iat": now,
+            "exp": exp,
+        }),
+        None if !config.scopes.is_empty() => json!({
+            "iss": key.client_email,
+            "sub": key.client_email,
+            "scope": config.scopes.join(" "),
+            "iat": now,
+            "exp": exp,
+        }),
+        None => return Err(LibError::NoJwtAudience(config.name.clone())),
+    };
+    Ok(Token::new(key.sign_jwt(&claims)?, exp))
+}
+
+async fn request_token(
+    http_client: &reqwest::Client,
+    token_uri: &str,
+    assertion: &str,
+) -> Result<TokenResponse, LibError> {
+    let response = http_client
+        .post(token_uri)
+        .form(&[("grant_type", JWT_BEARER_GRANT_TYPE), ("assertion", assertion)])
+        .send()
+        .await
+        .map_err(|e| LibError::OpenIdError(e.to_string()))?;
+    let status = response.status();
+    let body = response.text().await.map_err(|e| LibError::OpenIdError(e.to_string()))?;
+    let unsuccessful = || LibError::AuthorizationError(format!("The token endpoint responded with {}: {}", status, body));
+
+    // The OAuth errors are JSON, but other failures, e.g. of a proxy, may not be.
+    let response: TokenResponse = match serde_json::from_str(&body) {
+        Ok(response) => response,
+        Err(_) if !status.is_success() => return Err(unsuccessful()),
+        Err(e) => return Err(e.into()),
+    };
+
+    if let Some(error) = response.error {
+        return Err(LibError::AuthorizationError(match response.error_description {
+            Some(description) => format!("{}: {}", error, description),
+            None => error,
+        }));
+    }
+    if !status.is_success() {
+        return Err(unsuccessful());
+    }
+    Ok(response)
+}
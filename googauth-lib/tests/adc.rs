@@ -0,0 +1,105 @@
+//! Application Default Credentials shared with gcloud and the Google client libraries.
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::time::Duration;
+
+use googauth_lib::{ConfigFile, CredentialStore, LibError, MemoryStore, ADC_FILE_NAME};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+
+fn scopes() -> Vec<String> {
+    vec!["openid".to_string()]
+}
+
+#[tokio::test]
+async fn exports_and_imports_authorized_user_credentials() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("gcloud/application_default_credentials.json");
+    let mut config = ConfigFile::new("work", "client-id", "client-secret", &scopes(), "http://localhost");
+    config.refresh_token = Some("refresh-token".to_string());
+
+    config.export_adc(&path).unwrap();
+    let adc: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(
+        adc,
+        json!({
+            "type": "authorized_user",
+            "client_id": "client-id",
+            "client_secret": "client-secret",
+            "refresh_token": "refresh-token",
+        })
+    );
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+    let store = MemoryStore::new();
+    ConfigFile::import_adc("imported", &path, &scopes(), &store).await.unwrap();
+    let imported = store.load("imported").unwrap();
+    assert_eq!(imported.client_id, "client-id");
+    assert_eq!(imported.client_secret, "client-secret");
+    assert_eq!(imported.refresh_token.as_deref(), Some("refresh-token"));
+    assert!(imported.access_token.is_none());
+
+    assert!(matches!(
+        ConfigFile::import_adc("imported", &path, &scopes(), &store).await,
+        Err(LibError::ConfigurationAlreadyExists(_))
+    ));
+}
+
+#[test]
+fn does_not_export_without_a_refresh_token() {
+    let config = ConfigFile::new("work", "client-id", "client-secret", &scopes(), "http://localhost");
+    assert!(matches!(config.to_adc_json(), Err(LibError::NoRefreshTokenForConfig(_))));
+}
+
+#[test]
+fn rejects_other_types_of_credentials() {
+    let service_account = r#"{"type":"service_account","client_email":"sa@project.iam.gserviceaccount.com"}"#;
+    assert!(matches!(
+        ConfigFile::from_adc_json("work", service_account, &scopes()),
+        Err(LibError::InvalidApplicationDefaultCredentials(_))
+    ));
+}
+
+#[test]
+fn replaces_the_credentials_through_a_temporary_file_and_keeps_a_backup() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join(ADC_FILE_NAME);
+    let mut config = ConfigFile::new("work", "client-id", "client-secret", &scopes(), "http://localhost");
+    config.refresh_token = Some("first".to_string());
+    config.export_adc(&path).unwrap();
+    config.refresh_token = Some("second".to_string());
+    config.export_adc(&path).unwrap();
+
+    assert!(fs::read_to_string(&path).unwrap().contains("second"));
+    let backup = dir.path().join(format!(".{}.bak", ADC_FILE_NAME));
+    assert!(fs::read_to_string(&backup).unwrap().contains("first"));
+    assert_eq!(fs::metadata(&backup).unwrap().permissions().mode() & 0o777, 0o600);
+    let mut file_names: Vec<String> =
+        fs::read_dir(dir.path()).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+    file_names.sort();
+    assert_eq!(file_names, [format!(".{}.bak", ADC_FILE_NAME), ADC_FILE_NAME.to_string()]);
+}
+
+#[tokio::test]
+async fn imports_while_holding_the_lock_of_the_profile() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join(ADC_FILE_NAME);
+    let mut config = ConfigFile::new("work", "client-id", "client-secret", &scopes(), "http://localhost");
+    config.refresh_token = Some("refresh-token".to_string());
+    config.export_adc(&path).unwrap();
+
+    let store = MemoryStore::new();
+    let lock = store.try_lock("imported").unwrap();
+    let scopes = scopes();
+    let import = ConfigFile::import_adc("imported", &path, &scopes, &store);
+    let release = async {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(matches!(store.load("imported"), Err(LibError::NoSuchConfiguration(_))));
+        drop(lock);
+    };
+    let (imported, _) = tokio::join!(import, release);
+    imported.unwrap();
+    assert!(store.load("imported").is_ok());
+    assert!(store.try_lock("imported").unwrap().is_some());
+}
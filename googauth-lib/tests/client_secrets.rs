@@ -0,0 +1,45 @@
+//! Profiles created from the client_secret.json files of the Google Cloud console.
+
+use googauth_lib::{ConfigFile, LibError};
+
+const INSTALLED: &str = r#"{"installed":{"client_id":"123-abc.apps.googleusercontent.com","project_id":"my-project","auth_uri":"https://accounts.google.com/o/oauth2/auth","token_uri":"https://oauth2.googleapis.com/token","auth_provider_x509_cert_url":"https://www.googleapis.com/oauth2/v1/certs","client_secret":"installed-secret","redirect_uris":["http://localhost"]}}"#;
+
+const WEB: &str = r#"{"web":{"client_id":"456-web.apps.googleusercontent.com","project_id":"my-project","auth_uri":"https://accounts.google.com/o/oauth2/auth","token_uri":"https://oauth2.googleapis.com/token","client_secret":"web-secret","redirect_uris":["https://app.example.com/callback","http://127.0.0.1:9000/callback"]}}"#;
+
+fn scopes() -> Vec<String> {
+    vec!["openid".to_string()]
+}
+
+#[test]
+fn imports_installed_clients_with_a_random_port() {
+    let config = ConfigFile::from_client_secrets_json("work", INSTALLED, &scopes()).unwrap();
+    assert_eq!(config.name, "work");
+    assert_eq!(config.client_id, "123-abc.apps.googleusercontent.com");
+    assert_eq!(config.client_secret, "installed-secret");
+    assert_eq!(config.redirect_url, "http://localhost:0/");
+    assert_eq!(config.scopes, scopes());
+}
+
+#[test]
+fn imports_web_clients_with_their_loopback_redirect_uri() {
+    let config = ConfigFile::from_client_secrets_json("work", WEB, &scopes()).unwrap();
+    assert_eq!(config.client_id, "456-web.apps.googleusercontent.com");
+    assert_eq!(config.client_secret, "web-secret");
+    assert_eq!(config.redirect_url, "http://127.0.0.1:9000/callback");
+}
+
+#[test]
+fn rejects_invalid_files() {
+    let no_loopback = WEB.replace("http://127.0.0.1:9000/callback", "https://other.example.com/callback");
+    let other_endpoint = INSTALLED.replace("https://oauth2.googleapis.com/token", "http://oauth2.googleapis.com/token");
+    for json in [r#"{"other":{}}"#, "not json", &no_loopback, &other_endpoint] {
+        assert!(
+            matches!(
+                ConfigFile::from_client_secrets_json("work", json, &scopes()),
+                Err(LibError::InvalidClientSecrets(_))
+            ),
+            "{} should be rejected",
+            json
+        );
+    }
+}
@@ -0,0 +1,249 @@
+//! How configurations are saved, locked and deleted in a configuration directory.
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use googauth_lib::{lock_profile, ConfigBasePath, ConfigEnvironment, ConfigFile, CredentialStore, LibError, ProfileName, Token};
+use tempfile::TempDir;
+
+fn profile(name: &str) -> ProfileName {
+    ProfileName::new(name).unwrap()
+}
+
+fn config(access_token: &str) -> ConfigFile {
+    let scopes = vec!["openid".to_string()];
+    let mut config = ConfigFile::new("myprofile", "client-id", "client-secret", &scopes, "http://localhost");
+    config.refresh_token = Some("refresh-token".to_string());
+    config.access_token = Some(Token::new(access_token.to_string(), 1700003600));
+    config
+}
+
+fn file_names(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn saves_through_a_temporary_file_and_keeps_a_backup() {
+    let dir = TempDir::new().unwrap();
+    let base_path = ConfigBasePath::from(dir.path().to_path_buf());
+    config("first").save_config(&base_path).unwrap();
+    config("second").save_config(&base_path).unwrap();
+
+    let token_cache = ConfigFile::token_cache_file(&profile("myprofile"), &base_path);
+    assert!(fs::read_to_string(&token_cache).unwrap().contains("second"));
+    assert_eq!(fs::metadata(&token_cache).unwrap().permissions().mode() & 0o777, 0o600);
+    // The temporary files are renamed over the files, and the previous token cache is kept.
+    assert_eq!(file_names(dir.path()), ["myprofile", "tokens"]);
+    assert_eq!(file_names(&dir.path().join("tokens")), [".myprofile.bak", "myprofile"]);
+    assert!(fs::read_to_string(dir.path().join("tokens/.myprofile.bak")).unwrap().contains("first"));
+}
+
+#[test]
+fn removes_the_temporary_file_if_it_can_not_be_renamed() {
+    let dir = TempDir::new().unwrap();
+    let base_path = ConfigBasePath::from(dir.path().to_path_buf());
+    // A directory in place of the token cache, which the temporary file can not be renamed over.
+    fs::create_dir_all(dir.path().join("tokens/myprofile/cache")).unwrap();
+
+    assert!(matches!(config("first").save_config(&base_path), Err(LibError::IoError(_))));
+    assert_eq!(file_names(&dir.path().join("tokens")), ["myprofile"]);
+}
+
+#[test]
+fn falls_back_to_the_backup_of_a_corrupt_file() {
+    let dir = TempDir::new().unwrap();
+    let base_path = ConfigBasePath::from(dir.path().to_path_buf());
+    config("first").save_config(&base_path).unwrap();
+    config("second").save_config(&base_path).unwrap();
+
+    let token_cache = ConfigFile::token_cache_file(&profile("myprofile"), &base_path);
+    fs::write(&token_cache, "{\"version\":1,\"na").unwrap();
+    let read = ConfigFile::read_config(&profile("myprofile"), &base_path).unwrap();
+    assert_eq!(read.access_token.unwrap().secret, "first");
+
+    // A corrupt file never replaces a good backup.
+    config("third").save_config(&base_path).unwrap();
+    assert!(fs::read_to_string(dir.path().join("tokens/.myprofile.bak")).unwrap().contains("first"));
+}
+
+#[test]
+fn locks_profiles() {
+    let dir = TempDir::new().unwrap();
+    let base_path = ConfigBasePath::from(dir.path().to_path_buf());
+
+    let lock = base_path.try_lock("myprofile").unwrap();
+    assert!(lock.is_some());
+    assert!(base_path.try_lock("myprofile").unwrap().is_none());
+    assert!(base_path.try_lock("other").unwrap().is_some());
+
+    drop(lock);
+    assert!(base_path.try_lock("myprofile").unwrap().is_some());
+}
+
+#[tokio::test]
+async fn waits_for_a_locked_profile() {
+    let dir = TempDir::new().unwrap();
+    let base_path = ConfigBasePath::from(dir.path().to_path_buf());
+    let lock = base_path.try_lock("myprofile").unwrap().unwrap();
+
+    let start = Instant::now();
+    let release = async {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        drop(lock);
+    };
+    let (_, lock) = tokio::join!(release, lock_profile(&base_path, "myprofile"));
+    assert!(lock.is_ok());
+    assert!(start.elapsed() >= Duration::from_millis(300));
+}
+
+#[tokio::test]
+async fn locks_profiles_through_config_files_and_stores_alike() {
+    let dir = TempDir::new().unwrap();
+    let base_path = ConfigBasePath::from(dir.path().to_path_buf());
+
+    let lock = ConfigFile::lock(&profile("myprofile"), &base_path).await.unwrap();
+    assert!(base_path.try_lock("myprofile").unwrap().is_none());
+    drop(lock);
+
+    let lock = lock_profile(&base_path, "myprofile").await.unwrap();
+    assert!(ConfigFile::try_lock(&profile("myprofile"), &base_path).unwrap().is_none());
+    drop(lock);
+    assert!(ConfigFile::try_lock(&profile("myprofile"), &base_path).unwrap().is_some());
+}
+
+#[test]
+fn deletes_and_renames_lock_files() {
+    let dir = TempDir::new().unwrap();
+    let base_path = ConfigBasePath::from(dir.path().to_path_buf());
+    let tokens = dir.path().join("tokens");
+    config("first").save_config(&base_path).unwrap();
+    drop(base_path.try_lock("myprofile").unwrap());
+    assert!(tokens.join(".myprofile.lock").exists());
+
+    ConfigFile::rename_config(&profile("myprofile"), &profile("renamed"), &base_path).unwrap();
+    drop(base_path.try_lock("renamed").unwrap());
+    assert_eq!(file_names(&tokens), [".renamed.lock", "renamed"]);
+
+    ConfigFile::delete_config(&profile("renamed"), &base_path).unwrap();
+    assert!(file_names(&tokens).is_empty());
+}
+
+#[test]
+fn asks_for_each_passphrase_once_and_lists_locked_profiles() {
+    let dir = TempDir::new().unwrap();
+    let passphrases = |asked: Arc<Mutex<Vec<String>>>| {
+        Arc::new(move |name: &str| {
+            asked.lock().unwrap().push(name.to_string());
+            match name {
+                "other" => Ok("battery-staple".to_string()),
+                _ => Ok("correct-horse".to_string()),
+            }
+        })
+    };
+    let asked = Arc::new(Mutex::new(Vec::new()));
+    let base_path = ConfigBasePath::from(dir.path().to_path_buf()).with_passphrase_provider(passphrases(asked.clone()));
+    for name in ["myprofile", "shared", "other"] {
+        let mut config = config("token");
+        config.name = name.to_string();
+        config.encrypted = true;
+        config.save_config(&base_path).unwrap();
+    }
+
+    // The passphrase of myprofile also decrypts shared, and is not asked for again.
+    let asked = Arc::new(Mutex::new(Vec::new()));
+    let base_path = ConfigBasePath::from(dir.path().to_path_buf()).with_passphrase_provider(passphrases(asked.clone()));
+    assert_eq!(ConfigFile::read_config(&profile("myprofile"), &base_path).unwrap().access_token.unwrap().secret, "token");
+    assert_eq!(ConfigFile::read_config(&profile("shared"), &base_path).unwrap().name, "shared");
+    assert_eq!(ConfigFile::read_config(&profile("other"), &base_path).unwrap().name, "other");
+    assert_eq!(*asked.lock().unwrap(), ["myprofile", "other"]);
+
+    // A wrong passphrase is not remembered, and the profiles that it does not decrypt are locked.
+    let base_path = ConfigBasePath::from(dir.path().to_path_buf())
+        .with_passphrase_provider(Arc::new(|_| Ok("battery-staple".to_string())));
+    assert!(matches!(ConfigFile::read_config(&profile("myprofile"), &base_path), Err(LibError::DecryptionFailed(_))));
+    let list = ConfigFile::scan_configs(&base_path).unwrap();
+    assert_eq!(list.configs.iter().map(|config| config.name.as_str()).collect::<Vec<_>>(), ["other"]);
+    let mut locked = list.locked;
+    locked.sort();
+    assert_eq!(locked, ["myprofile", "shared"]);
+
+    let base_path = ConfigBasePath::from(dir.path().to_path_buf()).with_passphrase_provider(Arc::new(|_| Ok(String::new())));
+    assert!(matches!(ConfigFile::read_config(&profile("myprofile"), &base_path), Err(LibError::PassphraseRequired(_))));
+}
+
+#[test]
+fn sets_random_redirect_ports() {
+    for (redirect_url, expected) in [
+        ("http://localhost:8080/", "http://localhost:0/"),
+        ("http://127.0.0.1/callback", "http://127.0.0.1:0/callback"),
+        ("http://[::1]:9000/", "http://[::1]:0/"),
+    ] {
+        let mut config = ConfigFile::new("myprofile", "client-id", "client-secret", &[], redirect_url);
+        config.set_random_redirect_port().unwrap();
+        assert_eq!(config.redirect_url, expected);
+    }
+
+    let mut config = ConfigFile::new("myprofile", "client-id", "client-secret", &[], "urn:ietf:wg:oauth:2.0:oob");
+    assert!(matches!(config.set_random_redirect_port(), Err(LibError::NoRedirectPortError)));
+}
+
+/// Where the profile `myprofile` and its token cache are saved with the configuration directory and the environment.
+fn resolved_paths(config_dir: Option<&Path>, environment: &ConfigEnvironment) -> (PathBuf, PathBuf) {
+    let base_path = ConfigBasePath::resolve(config_dir.map(Path::to_path_buf), environment).unwrap();
+    (
+        ConfigFile::config_file(&profile("myprofile"), &base_path),
+        ConfigFile::token_cache_file(&profile("myprofile"), &base_path),
+    )
+}
+
+#[test]
+fn resolves_the_configuration_directory_in_order_of_preference() {
+    let home = TempDir::new().unwrap();
+    let h = home.path();
+    let mut environment = ConfigEnvironment {
+        googauth_home: Some(h.join("googauth-home")),
+        xdg_config_home: Some(h.join("xdg-config")),
+        xdg_state_home: Some(h.join("xdg-state")),
+        home: Some(h.to_path_buf()),
+    };
+
+    // A configuration directory, e.g. of --config-dir, comes before all of the environment.
+    let paths = resolved_paths(Some(&h.join("config-dir")), &environment);
+    assert_eq!(paths, (h.join("config-dir/myprofile"), h.join("config-dir/tokens/myprofile")));
+
+    let paths = resolved_paths(None, &environment);
+    assert_eq!(paths, (h.join("googauth-home/myprofile"), h.join("googauth-home/tokens/myprofile")));
+
+    environment.googauth_home = None;
+    let paths = resolved_paths(None, &environment);
+    assert_eq!(paths, (h.join("xdg-config/googauth/myprofile"), h.join("xdg-state/googauth/myprofile")));
+
+    environment.xdg_config_home = None;
+    environment.xdg_state_home = None;
+    let paths = resolved_paths(None, &environment);
+    assert_eq!(paths, (h.join(".config/googauth/myprofile"), h.join(".local/state/googauth/myprofile")));
+
+    // An existing ~/.googauth is kept, with its tokens in the state directory.
+    fs::create_dir(h.join(".googauth")).unwrap();
+    let paths = resolved_paths(None, &environment);
+    assert_eq!(paths, (h.join(".googauth/myprofile"), h.join(".local/state/googauth/myprofile")));
+
+    environment.xdg_config_home = Some(h.join("xdg-config"));
+    environment.xdg_state_home = Some(h.join("xdg-state"));
+    let paths = resolved_paths(None, &environment);
+    assert_eq!(paths, (h.join(".googauth/myprofile"), h.join("xdg-state/googauth/myprofile")));
+
+    // The home directory is only needed without a configuration directory or $GOOGAUTH_HOME.
+    environment.home = None;
+    assert!(matches!(ConfigBasePath::resolve(None, &environment), Err(LibError::HomeDirectoryNotFound)));
+    environment.googauth_home = Some(h.join("googauth-home"));
+    assert_eq!(resolved_paths(None, &environment).0, h.join("googauth-home/myprofile"));
+}
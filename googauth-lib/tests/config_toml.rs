@@ -0,0 +1,186 @@
+//! Profiles defined in a hand-written config.toml, with shared client registrations.
+
+use std::fs;
+use std::time::{Duration, SystemTime};
+
+use googauth_lib::{ConfigBasePath, ConfigFile, DeletedConfig, LibError, ProfileName, Token};
+use tempfile::TempDir;
+
+fn profile(name: &str) -> ProfileName {
+    ProfileName::new(name).unwrap()
+}
+
+const CONFIG_TOML: &str = r#"
+[defaults]
+client = "corp"
+scopes = ["openid", "email"]
+
+[clients.corp]
+client_id = "corp-id"
+client_secret = "corp-secret"
+
+[clients.partner]
+client_id = "partner-id"
+client_secret = "partner-secret"
+redirect_url = "http://127.0.0.1:0/"
+issuer_url = "https://partner.example.com"
+
+[profiles.work]
+
+[profiles.partner]
+client = "partner"
+scopes = ["openid"]
+min_validity = 300
+"#;
+
+fn base_path(config_toml: &str) -> (TempDir, ConfigBasePath) {
+    let dir = TempDir::new().unwrap();
+    fs::write(dir.path().join("config.toml"), config_toml).unwrap();
+    let base_path = ConfigBasePath::from(dir.path().to_path_buf());
+    (dir, base_path)
+}
+
+#[test]
+fn resolves_profiles_with_defaults() {
+    let (_dir, base_path) = base_path(CONFIG_TOML);
+
+    let work = ConfigFile::read_config(&profile("work"), &base_path).unwrap();
+    assert_eq!(work.client_id, "corp-id");
+    assert_eq!(work.client_secret, "corp-secret");
+    assert_eq!(work.scopes, vec!["openid", "email"]);
+    assert_eq!(work.redirect_url, "http://localhost:8080/");
+    assert_eq!(work.issuer_url, "https://accounts.google.com");
+    assert_eq!(work.min_validity, None);
+
+    let partner = ConfigFile::read_config(&profile("partner"), &base_path).unwrap();
+    assert_eq!(partner.client_id, "partner-id");
+    assert_eq!(partner.scopes, vec!["openid"]);
+    assert_eq!(partner.redirect_url, "http://127.0.0.1:0/");
+    assert_eq!(partner.issuer_url, "https://partner.example.com");
+    assert_eq!(partner.min_validity, Some(300));
+
+    let mut names: Vec<String> = ConfigFile::list_configs(&base_path)
+        .unwrap()
+        .into_iter()
+        .map(|config| config.name)
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["partner", "work"]);
+}
+
+#[test]
+fn saves_only_the_tokens_of_profiles_in_config_toml() {
+    let (dir, base_path) = base_path(CONFIG_TOML);
+
+    let mut work = ConfigFile::read_config(&profile("work"), &base_path).unwrap();
+    work.access_token = Some(Token::new("access-token".to_string(), 1700003600));
+    work.save_config(&base_path).unwrap();
+
+    assert!(!dir.path().join("work").exists());
+    assert!(dir.path().join("tokens/work").exists());
+    let work = ConfigFile::read_config(&profile("work"), &base_path).unwrap();
+    assert_eq!(work.access_token.unwrap().secret, "access-token");
+}
+
+#[test]
+fn profile_files_take_precedence() {
+    let (dir, base_path) = base_path(CONFIG_TOML);
+    fs::write(
+        dir.path().join("work"),
+        r#"{"version":2,"name":"work","client_id":"file-id","client_secret":"file-secret","scopes":["openid"],"redirect_url":"http://localhost"}"#,
+    )
+    .unwrap();
+
+    assert_eq!(ConfigFile::read_config(&profile("work"), &base_path).unwrap().client_id, "file-id");
+}
+
+#[test]
+fn rejects_undefined_clients() {
+    let (_dir, base_path) = base_path("[profiles.work]\nclient = \"missing\"\nscopes = [\"openid\"]\n");
+
+    assert!(matches!(
+        ConfigFile::read_config(&profile("work"), &base_path),
+        Err(LibError::InvalidConfigToml(_))
+    ));
+}
+
+#[test]
+fn reads_config_toml_again_when_it_changes() {
+    let (dir, base_path) = base_path(CONFIG_TOML);
+    assert!(ConfigFile::is_defined_in_config_toml(&profile("work"), &base_path).unwrap());
+    assert!(!ConfigFile::is_defined_in_config_toml(&profile("home"), &base_path).unwrap());
+
+    let path = dir.path().join("config.toml");
+    fs::write(&path, CONFIG_TOML.replace("[profiles.work]", "[profiles.home]")).unwrap();
+    // Make sure that the modification time changes, also on file systems with a coarse resolution.
+    let file = fs::File::options().write(true).open(&path).unwrap();
+    file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+
+    assert!(ConfigFile::is_defined_in_config_toml(&profile("home"), &base_path).unwrap());
+    assert!(matches!(
+        ConfigFile::read_config(&profile("work"), &base_path),
+        Err(LibError::NoSuchConfiguration(_))
+    ));
+}
+
+#[test]
+fn reports_profiles_in_config_toml_that_can_not_be_read() {
+    let (dir, base_path) = base_path(CONFIG_TOML);
+    fs::create_dir_all(dir.path().join("tokens")).unwrap();
+    fs::write(dir.path().join("tokens/work"), r#"{"version":99}"#).unwrap();
+
+    let list = ConfigFile::scan_configs(&base_path).unwrap();
+    assert_eq!(list.configs.iter().map(|config| config.name.as_str()).collect::<Vec<_>>(), ["partner"]);
+    assert!(matches!(
+        list.errors.as_slice(),
+        [(name, LibError::UnsupportedConfigVersion(_, _))] if name == "work"
+    ));
+}
+
+#[test]
+fn reports_profiles_in_config_toml_that_can_not_be_resolved() {
+    let config_toml = format!("{}\n[profiles.broken]\nclient = \"nope\"\n\n[profiles.tokens]\n", CONFIG_TOML);
+    let (_dir, base_path) = base_path(&config_toml);
+
+    let mut list = ConfigFile::scan_configs(&base_path).unwrap();
+    let mut names: Vec<_> = list.configs.iter().map(|config| config.name.as_str()).collect();
+    names.sort();
+    assert_eq!(names, ["partner", "work"]);
+    list.errors.sort_by(|a, b| a.0.cmp(&b.0));
+    assert!(matches!(
+        list.errors.as_slice(),
+        [(broken, LibError::InvalidConfigToml(_)), (tokens, LibError::InvalidProfileName(_))]
+            if broken == "broken" && tokens == "tokens"
+    ));
+
+    // The other profiles can still be read, copied and saved.
+    let mut config = ConfigFile::read_config(&profile("work"), &base_path).unwrap();
+    ConfigFile::copy_config(&profile("work"), &profile("work2"), &base_path).unwrap();
+    assert_eq!(ConfigFile::read_config(&profile("work2"), &base_path).unwrap().client_id, "corp-id");
+    config.access_token = Some(Token::new("access-token".to_string(), 1700003600));
+    config.save_config(&base_path).unwrap();
+    assert!(matches!(
+        ConfigFile::read_config(&profile("broken"), &base_path),
+        Err(LibError::InvalidConfigToml(_))
+    ));
+}
+
+#[test]
+fn deletes_only_the_tokens_of_profiles_in_config_toml() {
+    let (dir, base_path) = base_path(CONFIG_TOML);
+    let mut work = ConfigFile::read_config(&profile("work"), &base_path).unwrap();
+    work.access_token = Some(Token::new("access-token".to_string(), 1700003600));
+    work.save_config(&base_path).unwrap();
+
+    assert_eq!(ConfigFile::delete_config(&profile("work"), &base_path).unwrap(), DeletedConfig::Tokens);
+    assert!(!dir.path().join("tokens/work").exists());
+    let work = ConfigFile::read_config(&profile("work"), &base_path).unwrap();
+    assert!(work.access_token.is_none());
+
+    ConfigFile::new("home", "home-id", "home-secret", &[], "http://localhost").save_config(&base_path).unwrap();
+    assert_eq!(ConfigFile::delete_config(&profile("home"), &base_path).unwrap(), DeletedConfig::Profile);
+    assert!(matches!(
+        ConfigFile::delete_config(&profile("home"), &base_path),
+        Err(LibError::NoSuchConfiguration(_))
+    ));
+}
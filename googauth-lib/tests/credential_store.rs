@@ -0,0 +1,81 @@
+//! The contract of the CredentialStore trait, for each store in the library.
+
+use googauth_lib::{ConfigBasePath, ConfigFile, CredentialStore, LibError, MemoryStore, ProfileName, Token};
+use tempfile::TempDir;
+
+fn config(name: &str, access_token: &str) -> ConfigFile {
+    let scopes = vec!["openid".to_string()];
+    let mut config = ConfigFile::new(name, "client-id", "client-secret", &scopes, "http://localhost");
+    config.refresh_token = Some("refresh-token".to_string());
+    config.access_token = Some(Token::new(access_token.to_string(), 1700003600));
+    config
+}
+
+fn names<S: CredentialStore>(store: &S) -> Vec<String> {
+    let mut names: Vec<String> = store.list().unwrap().into_iter().map(|config| config.name).collect();
+    names.sort();
+    names
+}
+
+fn loads_what_was_saved<S: CredentialStore>(store: &S) {
+    assert!(matches!(store.load("work"), Err(LibError::NoSuchConfiguration(_))));
+
+    store.save(&config("work", "first")).unwrap();
+    let loaded = store.load("work").unwrap();
+    assert_eq!(loaded.client_id, "client-id");
+    assert_eq!(loaded.refresh_token.as_deref(), Some("refresh-token"));
+    assert_eq!(loaded.access_token.unwrap().secret, "first");
+
+    store.save(&config("work", "second")).unwrap();
+    assert_eq!(store.load("work").unwrap().access_token.unwrap().secret, "second");
+}
+
+fn lists_and_deletes<S: CredentialStore>(store: &S) {
+    assert!(names(store).is_empty());
+    store.save(&config("work", "token")).unwrap();
+    store.save(&config("home", "token")).unwrap();
+    assert_eq!(names(store), ["home", "work"]);
+
+    store.delete("work").unwrap();
+    assert_eq!(names(store), ["home"]);
+    assert!(matches!(store.load("work"), Err(LibError::NoSuchConfiguration(_))));
+    assert!(matches!(store.delete("work"), Err(LibError::NoSuchConfiguration(_))));
+}
+
+fn locks_each_profile_once<S: CredentialStore>(store: &S) {
+    let lock = store.try_lock("work").unwrap();
+    assert!(lock.is_some());
+    assert!(store.try_lock("work").unwrap().is_none());
+    assert!(store.try_lock("home").unwrap().is_some());
+
+    drop(lock);
+    assert!(store.try_lock("work").unwrap().is_some());
+}
+
+fn check_contract<S: CredentialStore>(mut new_store: impl FnMut() -> S) {
+    loads_what_was_saved(&new_store());
+    lists_and_deletes(&new_store());
+    locks_each_profile_once(&new_store());
+}
+
+#[test]
+fn memory_store() {
+    check_contract(MemoryStore::new);
+}
+
+#[test]
+fn config_base_path() {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let mut dirs = dirs.iter();
+    check_contract(|| ConfigBasePath::from(dirs.next().unwrap().path().to_path_buf()));
+}
+
+#[tokio::test]
+async fn config_file_lock_excludes_other_locks() {
+    let dir = TempDir::new().unwrap();
+    let base_path = ConfigBasePath::from(dir.path().to_path_buf());
+    let lock = ConfigFile::lock(&ProfileName::new("work").unwrap(), &base_path).await.unwrap();
+    assert!(base_path.try_lock("work").unwrap().is_none());
+    drop(lock);
+    assert!(base_path.try_lock("work").unwrap().is_some());
+}
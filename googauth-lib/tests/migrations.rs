@@ -0,0 +1,185 @@
+//! Reads configurations saved in each historical format, from the fixtures directory.
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use googauth_lib::{
+    ConfigBasePath, ConfigFile, CredentialStore, LibError, ProfileName, CONFIG_VERSION, ENCRYPTED_CONFIG_VERSION, TOKEN_CACHE_VERSION,
+};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+
+const PASSPHRASE: &str = "correct-horse";
+
+fn myprofile() -> ProfileName {
+    ProfileName::new("myprofile").unwrap()
+}
+
+/// Copy a fixture to a temporary directory, since reading it migrates the files.
+fn fixture(name: &str) -> TempDir {
+    let dir = TempDir::new().unwrap();
+    copy_dir(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name), dir.path());
+    dir
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        if entry.path().is_dir() {
+            copy_dir(&entry.path(), &to.join(entry.file_name()));
+        } else {
+            fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+        }
+    }
+}
+
+fn base_path(dir: &TempDir) -> ConfigBasePath {
+    ConfigBasePath::from(dir.path().to_path_buf())
+        .with_passphrase_provider(Arc::new(|_| Ok(PASSPHRASE.to_string())))
+}
+
+fn read_json(path: &Path) -> Value {
+    serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+}
+
+fn assert_fixture_config(config: &ConfigFile) {
+    assert_eq!(config.version, CONFIG_VERSION);
+    assert_eq!(config.name, "myprofile");
+    assert_eq!(config.client_id, "my-client-id.apps.googleusercontent.com");
+    assert_eq!(config.client_secret, "my-client-secret");
+    assert_eq!(config.scopes, vec!["openid", "email"]);
+    assert_eq!(config.redirect_url, "http://localhost:8080");
+    assert_eq!(config.issuer_url, "https://accounts.google.com");
+    assert_eq!(config.refresh_token.as_deref(), Some("my-refresh-token"));
+    assert_eq!(config.id_token.as_ref().unwrap().secret, "my-id-token");
+    assert_eq!(config.access_token.as_ref().unwrap().secret, "my-access-token");
+    assert_eq!(config.access_token.as_ref().unwrap().exp, 1700003600);
+}
+
+#[test]
+fn reads_and_migrates_version_1() {
+    let dir = fixture("v1");
+    let config = ConfigFile::read_config(&myprofile(), &base_path(&dir)).unwrap();
+    assert_fixture_config(&config);
+    assert_eq!(config.min_validity, None);
+    assert!(!config.encrypted);
+
+    let profile = read_json(&dir.path().join("myprofile"));
+    assert_eq!(profile["version"], json!(CONFIG_VERSION));
+    assert!(profile.get("refresh_token").is_none());
+    assert!(profile.get("access_token").is_none());
+    let token_cache = read_json(&dir.path().join("tokens/myprofile"));
+    assert_eq!(token_cache["version"], json!(TOKEN_CACHE_VERSION));
+    assert_eq!(token_cache["refresh_token"], json!("my-refresh-token"));
+    assert!(!dir.path().join(".myprofile.bak").exists());
+
+    assert_fixture_config(&ConfigFile::read_config(&myprofile(), &base_path(&dir)).unwrap());
+}
+
+#[test]
+fn reads_and_migrates_encrypted_version_1() {
+    let dir = fixture("v1-encrypted");
+    let config = ConfigFile::read_config(&myprofile(), &base_path(&dir)).unwrap();
+    assert_fixture_config(&config);
+    assert_eq!(config.min_validity, Some(300));
+    assert!(config.encrypted);
+
+    let profile = read_json(&dir.path().join("myprofile"));
+    let token_cache = read_json(&dir.path().join("tokens/myprofile"));
+    assert_eq!(profile["version"], json!(ENCRYPTED_CONFIG_VERSION));
+    assert_eq!(token_cache["version"], json!(ENCRYPTED_CONFIG_VERSION));
+
+    assert_fixture_config(&ConfigFile::read_config(&myprofile(), &base_path(&dir)).unwrap());
+}
+
+#[test]
+fn reads_version_2_without_rewriting_it() {
+    let dir = fixture("v2");
+    let profile = fs::read(dir.path().join("myprofile")).unwrap();
+    let token_cache = fs::read(dir.path().join("tokens/myprofile")).unwrap();
+
+    let config = ConfigFile::read_config(&myprofile(), &base_path(&dir)).unwrap();
+    assert_fixture_config(&config);
+    assert_eq!(config.min_validity, Some(300));
+
+    assert_eq!(fs::read(dir.path().join("myprofile")).unwrap(), profile);
+    assert_eq!(fs::read(dir.path().join("tokens/myprofile")).unwrap(), token_cache);
+}
+
+#[test]
+fn reads_and_encrypts_again_encrypted_version_2() {
+    let dir = fixture("v2-encrypted");
+    assert_eq!(read_json(&dir.path().join("myprofile"))["version"], json!(1000));
+    let config = ConfigFile::read_config(&myprofile(), &base_path(&dir)).unwrap();
+    assert_fixture_config(&config);
+    assert!(config.encrypted);
+
+    // Files of the first encrypted version do not authenticate their kind, and are encrypted again.
+    let profile = read_json(&dir.path().join("myprofile"));
+    let token_cache = read_json(&dir.path().join("tokens/myprofile"));
+    assert_eq!(profile["version"], json!(ENCRYPTED_CONFIG_VERSION));
+    assert_eq!(token_cache["version"], json!(ENCRYPTED_CONFIG_VERSION));
+    assert!(!dir.path().join(".myprofile.bak").exists());
+
+    assert_fixture_config(&ConfigFile::read_config(&myprofile(), &base_path(&dir)).unwrap());
+}
+
+#[test]
+fn rejects_a_token_cache_in_place_of_a_profile() {
+    let dir = fixture("v2-encrypted");
+    ConfigFile::read_config(&myprofile(), &base_path(&dir)).unwrap();
+    fs::copy(dir.path().join("tokens/myprofile"), dir.path().join("myprofile")).unwrap();
+
+    assert!(matches!(
+        ConfigFile::read_config(&myprofile(), &base_path(&dir)),
+        Err(LibError::DecryptionFailed(_))
+    ));
+}
+
+#[test]
+fn migrates_only_when_the_profile_is_not_locked() {
+    let dir = fixture("v1");
+    let base_path = base_path(&dir);
+    let profile = fs::read(dir.path().join("myprofile")).unwrap();
+
+    let lock = base_path.try_lock("myprofile").unwrap().unwrap();
+    assert_fixture_config(&ConfigFile::read_config(&myprofile(), &base_path).unwrap());
+    assert_eq!(fs::read(dir.path().join("myprofile")).unwrap(), profile);
+    assert!(!dir.path().join("tokens/myprofile").exists());
+
+    drop(lock);
+    assert_fixture_config(&ConfigFile::read_config(&myprofile(), &base_path).unwrap());
+    assert_eq!(read_json(&dir.path().join("myprofile"))["version"], json!(CONFIG_VERSION));
+    assert!(dir.path().join("tokens/myprofile").exists());
+}
+
+#[test]
+fn rejects_newer_profile_version() {
+    let dir = fixture("v2");
+    let mut profile = read_json(&dir.path().join("myprofile"));
+    profile["version"] = json!(CONFIG_VERSION + 1);
+    fs::write(dir.path().join("myprofile"), profile.to_string()).unwrap();
+
+    match ConfigFile::read_config(&myprofile(), &base_path(&dir)) {
+        Err(LibError::UnsupportedConfigVersion(name, version)) => {
+            assert_eq!(name, "myprofile");
+            assert_eq!(version, CONFIG_VERSION as u64 + 1);
+        }
+        _ => panic!("expected UnsupportedConfigVersion"),
+    }
+}
+
+#[test]
+fn rejects_newer_token_cache_version() {
+    let dir = fixture("v2");
+    let mut token_cache = read_json(&dir.path().join("tokens/myprofile"));
+    token_cache["version"] = json!(TOKEN_CACHE_VERSION + 1);
+    fs::write(dir.path().join("tokens/myprofile"), token_cache.to_string()).unwrap();
+
+    assert!(matches!(
+        ConfigFile::read_config(&myprofile(), &base_path(&dir)),
+        Err(LibError::UnsupportedConfigVersion(_, _))
+    ));
+}
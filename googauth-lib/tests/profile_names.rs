@@ -0,0 +1,75 @@
+//! Profile names are used as file names, so they must not escape the configuration directory.
+
+use std::fs;
+
+use googauth_lib::{ConfigBasePath, ConfigFile, LibError, ProfileName};
+use tempfile::TempDir;
+
+fn profile(name: &str) -> ProfileName {
+    ProfileName::new(name).unwrap()
+}
+
+#[test]
+fn rejects_unsafe_names() {
+    for name in ["", ".", "..", ".hidden", "../x", "a/b", "a\\b", "a\nb", "a\0b", "config.toml", "tokens"] {
+        assert!(
+            matches!(ProfileName::new(name), Err(LibError::InvalidProfileName(_))),
+            "{:?} should be rejected",
+            name
+        );
+    }
+    for name in ["work", "my-profile", "my.profile", "a..b", "prod@example.com"] {
+        assert_eq!(ProfileName::new(name).unwrap().as_str(), name);
+    }
+}
+
+#[test]
+fn does_not_save_outside_the_configuration_directory() {
+    let dir = TempDir::new().unwrap();
+    let base_path = ConfigBasePath::from(dir.path().join("profiles"));
+    let config = ConfigFile::new("../escaped", "id", "secret", &["openid".to_string()], "http://localhost");
+
+    assert!(matches!(config.save_config(&base_path), Err(LibError::InvalidProfileName(_))));
+    assert!(!dir.path().join("escaped").exists());
+}
+
+#[test]
+fn does_not_save_over_the_token_cache_directory() {
+    let dir = TempDir::new().unwrap();
+    let base_path = ConfigBasePath::from(dir.path().to_path_buf());
+    ConfigFile::new("work", "id", "secret", &["openid".to_string()], "http://localhost").save_config(&base_path).unwrap();
+    let config = ConfigFile::new("tokens", "id", "secret", &["openid".to_string()], "http://localhost");
+
+    assert!(matches!(config.save_config(&base_path), Err(LibError::InvalidProfileName(_))));
+    let mut names: Vec<_> = fs::read_dir(dir.path().join("tokens"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["work"]);
+    assert!(fs::read_dir(dir.path()).unwrap().all(|entry| !entry.unwrap().file_name().to_string_lossy().ends_with(".tmp")));
+}
+
+#[test]
+fn reports_files_with_another_profile_name() {
+    let dir = TempDir::new().unwrap();
+    let base_path = ConfigBasePath::from(dir.path().to_path_buf());
+    let config = ConfigFile::new("work", "id", "secret", &["openid".to_string()], "http://localhost");
+    config.save_config(&base_path).unwrap();
+    fs::copy(dir.path().join("work"), dir.path().join("personal")).unwrap();
+
+    assert!(matches!(
+        ConfigFile::read_config(&profile("personal"), &base_path),
+        Err(LibError::ProfileNameMismatch(file_name, name)) if file_name == "personal" && name == "work"
+    ));
+
+    // Listing skips the file, and reports it apart from the profiles that could be read.
+    let configs = ConfigFile::list_configs(&base_path).unwrap();
+    assert_eq!(configs.iter().map(|config| config.name.as_str()).collect::<Vec<_>>(), ["work"]);
+    let list = ConfigFile::scan_configs(&base_path).unwrap();
+    assert_eq!(list.configs.len(), 1);
+    assert!(matches!(
+        list.errors.as_slice(),
+        [(file_name, LibError::ProfileNameMismatch(_, _))] if file_name == "personal"
+    ));
+}
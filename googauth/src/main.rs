@@ -1,10 +1,28 @@
-use std::process::exit;
+use std::env;
+use std::fs;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
 
 use googauth_lib::{
-    check_token, get_access_token_from_config, google_login, ConfigBasePath, ConfigFile,
+    check_token_with_min_validity, check_tokens_with_min_validity, default_adc_path,
+    get_access_token_from_config_as_subject, google_device_login, google_login_with_timeout,
+    google_manual_login, revoke_google_login, service_account_login, ConfigBasePath, ConfigEnvironment,
+    ConfigFile, ConfigList, DeletedConfig, LibError, PassphraseProvider, ProfileName, Token, ADC_SCOPES, CONFIG_TOML,
+    DEFAULT_REDIRECT_TIMEOUT, DEFAULT_REDIRECT_URL,
 };
+use serde_json::{json, Value};
+
+use crate::output::{
+    ensure_not_env, print_env, print_error_and_exit, print_json, print_lib_error_and_exit,
+    OutputFormat,
+};
+
+mod output;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -17,9 +35,46 @@ async fn main() {
         .help("The configuration name")
         .long_help("The configuration will cache the refresh token and other values to avoid reauthorization on reuse");
 
+    let min_valid_arg = Arg::with_name("min-valid")
+        .long("min-valid")
+        .takes_value(true)
+        .validator(|duration| parse_duration(&duration).map(|_| ()))
+        .help("Refresh the tokens if they expire within this time, e.g. 30s, 5m or 1h");
+
+    let adc_path_arg = Arg::with_name("path")
+        .value_name("PATH")
+        .index(2)
+        .help("The Application Default Credentials file, by default the one that gcloud uses");
+
+    let new_config_name_arg = Arg::with_name("new-config")
+        .value_name("NEW CONFIG NAME")
+        .required(true)
+        .index(2)
+        .help("The new configuration name");
+
     let app = App::new("googauth")
         .version(VERSION)
         .about("Request and store Google OpenID (OAuth) tokens")
+        .arg(
+            Arg::with_name("output")
+                .env("GOOGAUTH_OUTPUT")
+                .long("output")
+                .global(true)
+                .takes_value(true)
+                .possible_values(&OutputFormat::NAMES)
+                .default_value("text")
+                .help("The output format")
+                .long_help("The output format. json prints an object (or an array of objects) on standard out, and errors as {\"error\":{\"code\":...,\"message\":...}} on standard error. env prints shell variable assignments, and is supported by the accesstoken and idtoken commands."),
+        )
+        .arg(
+            Arg::with_name("config-dir")
+                .long("config-dir")
+                .global(true)
+                .takes_value(true)
+                .value_name("DIR")
+                .help("The directory to save the profiles in, with the tokens in its tokens subdirectory")
+                .long_help("The directory to save the profiles in, with the tokens in its tokens subdirectory. Defaults to $GOOGAUTH_HOME if it is set, else ~/.googauth if it exists, else $XDG_CONFIG_HOME/googauth with the tokens in $XDG_STATE_HOME/googauth."),
+        )
         .subcommand(SubCommand::with_name("list")
             .help("List all the current profiles")
         )
@@ -43,6 +98,14 @@ async fn main() {
                     .help("The client secret")
                     .long_help("The client secret. Can be created at http://console.developers.google.com"),
             )
+            .arg(
+                Arg::with_name("client-secrets-file")
+                    .long("client-secrets-file")
+                    .takes_value(true)
+                    .value_name("PATH")
+                    .help("Read the client id, secret and redirect URL from a client_secret.json file")
+                    .long_help("Read the client id, secret and redirect URL from a client_secret.json file, downloaded from the Google Cloud console, instead of --id and --secret. The first loopback redirect URI of the client is used, unless --redirect is given."),
+            )
             .arg(
                 Arg::with_name("scopes")
                     .env("SCOPES")
@@ -66,187 +129,952 @@ async fn main() {
                     .long("redirect")
                     .short("r")
                     .takes_value(true)
-                    .default_value("http://localhost:8080/")
+                    .default_value(DEFAULT_REDIRECT_URL)
                     .help("OAuth Redirect URL")
+                    .long_help("OAuth Redirect URL. Use port 0, e.g. http://127.0.0.1:0/, to let the operating system pick a free port on every login.")
+            )
+            .arg(
+                Arg::with_name("random-port")
+                    .long("random-port")
+                    .takes_value(false)
+                    .help("Listen for the redirect on a free port picked by the operating system")
+                    .long_help("Listen for the redirect on a free port picked by the operating system, by saving the redirect URL with port 0. Google desktop clients accept any loopback port."),
+            )
+            .arg(
+                Arg::with_name("issuer")
+                    .env("ISSUER")
+                    .long("issuer")
+                    .takes_value(true)
+                    .help("OpenID Connect issuer URL")
+                    .long_help("The OpenID Connect issuer URL used for discovery, e.g. a Keycloak realm, Dex or Okta. Defaults to https://accounts.google.com"),
+            )
+            .arg(
+                min_valid_arg
+                    .clone()
+                    .long_help("Refresh the tokens if they expire within this time, e.g. 30s, 5m or 1h. Saved in the profile, and used by the accesstoken, idtoken and exec commands unless they are given --min-valid.")
+            )
+            .arg(
+                Arg::with_name("service-account-key")
+                    .long("service-account-key")
+                    .takes_value(true)
+                    .value_name("PATH")
+                    .conflicts_with_all(&["client-secrets-file", "device", "manual", "random-port"])
+                    .help("Get the tokens with a service account JSON key, instead of signing in as a user")
+                    .long_help("Get the tokens with a service account JSON key, instead of signing in as a user. The key stays in its file, which is read whenever the tokens are refreshed. The scopes default to https://www.googleapis.com/auth/cloud-platform."),
+            )
+            .arg(
+                Arg::with_name("target-audience")
+                    .long("target-audience")
+                    .takes_value(true)
+                    .value_name("AUDIENCE")
+                    .help("The audience of the ID tokens of a service account, e.g. the URL of a Cloud Run service"),
+            )
+            .arg(
+                Arg::with_name("self-signed-jwt")
+                    .long("self-signed-jwt")
+                    .takes_value(false)
+                    .help("Sign the access tokens of a service account locally, instead of fetching them from Google")
+                    .long_help("Sign the access tokens of a service account locally, instead of fetching them from Google. Only Google APIs that accept self-signed JWTs accept these tokens. The tokens are for the scopes of the profile, or for the --jwt-audience."),
+            )
+            .arg(
+                Arg::with_name("jwt-audience")
+                    .long("jwt-audience")
+                    .takes_value(true)
+                    .value_name("AUDIENCE")
+                    .help("The audience of self-signed access tokens, e.g. https://pubsub.googleapis.com/ (implies --self-signed-jwt)"),
+            )
+            .arg(
+                Arg::with_name("subject")
+                    .long("subject")
+                    .takes_value(true)
+                    .value_name("USER")
+                    .conflicts_with_all(&["self-signed-jwt", "jwt-audience"])
+                    .help("The user that a service account acts as, with domain-wide delegation"),
+            )
+            .arg(
+                Arg::with_name("device")
+                    .long("device")
+                    .takes_value(false)
+                    .help("Sign in using a device code")
+                    .long_help("Sign in using the OAuth 2.0 device authorization grant. A URL and a code is printed, which can be entered in a browser on any device. Useful over SSH, in containers and on CI runners."),
+            )
+            .arg(
+                Arg::with_name("timeout")
+                    .long("timeout")
+                    .takes_value(true)
+                    .default_value("300")
+                    .validator(|timeout| {
+                        timeout
+                            .parse::<u64>()
+                            .map(|_| ())
+                            .map_err(|_| String::from("The timeout must be a number of seconds"))
+                    })
+                    .help("Seconds to wait for the browser to sign in")
+            )
+            .arg(
+                Arg::with_name("manual")
+                    .long("manual")
+                    .visible_alias("no-browser")
+                    .takes_value(false)
+                    .conflicts_with("device")
+                    .help("Sign in by pasting the redirect URL")
+                    .long_help("Do not open a browser or listen on the redirect port. The authorize URL is printed, and the URL the browser was redirected to (or only the code) is read from standard in."),
+            )
+            .arg(
+                Arg::with_name("encrypt")
+                    .long("encrypt")
+                    .takes_value(false)
+                    .help("Encrypt the profile with a passphrase")
+                    .long_help("Encrypt the profile with a passphrase, which is read from the GOOGAUTH_PASSPHRASE environment variable or asked for."),
+            )
+        )
+        .subcommand(SubCommand::with_name("encrypt")
+            .about("Encrypt a profile with a passphrase")
+            .long_about("Encrypt the client secret and the tokens of a profile with a passphrase. The passphrase is read from the GOOGAUTH_PASSPHRASE environment variable or asked for, and is then needed whenever the profile is used.")
+            .arg(config_name_arg.clone())
+        )
+        .subcommand(SubCommand::with_name("logout")
+            .about("Revoke the tokens of a profile and remove them from the profile")
+            .arg(config_name_arg.clone())
+            .arg(
+                Arg::with_name("delete")
+                    .long("delete")
+                    .takes_value(false)
+                    .help("Also delete the profile")
+            )
+        )
+        .subcommand(SubCommand::with_name("status")
+            .about("Report the cached token expiry times of every profile, without any network calls")
+            .arg(
+                Arg::with_name("config")
+                    .value_name("CONFIG NAME")
+                    .index(1)
+                    .help("Only report the status of this configuration")
+            )
+            .arg(
+                Arg::with_name("warn-within")
+                    .long("warn-within")
+                    .takes_value(true)
+                    .default_value("300")
+                    .validator(|duration| parse_duration(&duration).map(|_| ()))
+                    .help("Flag tokens that expire within this time, e.g. 30s, 5m or 1h")
+            )
+            .arg(
+                Arg::with_name("check")
+                    .long("check")
+                    .takes_value(false)
+                    .help("Exit with a non-zero code if any profile needs a new login")
+                    .long_help("Exit with code 1 if any profile lacks a refresh token and has an expired or soon-to-expire token, so that it needs a new login. Expired tokens of profiles that can refresh them are not a problem. Useful for scripted health checks.")
+            )
+        )
+        .subcommand(SubCommand::with_name("show")
+            .about("Show the settings and token expiry times of a profile, with secrets redacted")
+            .arg(config_name_arg.clone())
+        )
+        .subcommand(SubCommand::with_name("delete")
+            .about("Delete a profile")
+            .arg(config_name_arg.clone())
+        )
+        .subcommand(SubCommand::with_name("rename")
+            .about("Rename a profile, keeping its tokens")
+            .arg(config_name_arg.clone())
+            .arg(new_config_name_arg.clone())
+        )
+        .subcommand(SubCommand::with_name("copy")
+            .about("Copy the client settings of a profile to a new profile, without any tokens")
+            .arg(config_name_arg.clone())
+            .arg(new_config_name_arg.clone())
+        )
+        .subcommand(SubCommand::with_name("export-adc")
+            .about("Save the refresh token of a profile as gcloud Application Default Credentials")
+            .long_about("Save the client and refresh token of a profile as authorized_user Application Default Credentials, which Google client libraries and gcloud use. Overwrites the file, by default $CLOUDSDK_CONFIG/application_default_credentials.json or ~/.config/gcloud/application_default_credentials.json.")
+            .arg(config_name_arg.clone())
+            .arg(adc_path_arg.clone())
+        )
+        .subcommand(SubCommand::with_name("import-adc")
+            .about("Create a profile from gcloud Application Default Credentials")
+            .long_about("Create a profile with the client and refresh token of authorized_user Application Default Credentials, by default $CLOUDSDK_CONFIG/application_default_credentials.json or ~/.config/gcloud/application_default_credentials.json.")
+            .arg(config_name_arg.clone())
+            .arg(adc_path_arg.clone())
+            .arg(
+                Arg::with_name("scopes")
+                    .long("scopes")
+                    .takes_value(true)
+                    .use_delimiter(true)
+                    .help("The scopes that the credentials were granted, by default those of gcloud auth application-default login"),
+            )
+        )
+        .subcommand(SubCommand::with_name("exec")
+            .about("Run a command with fresh tokens in its environment")
+            .long_about("Refresh the tokens of a profile if needed, and replace googauth with the command. The tokens are set in the environment of the command, so they do not end up in the shell history. Since the command replaces googauth, it receives all signals and its exit code is the exit code of googauth.")
+            .arg(config_name_arg.clone())
+            .arg(
+                Arg::with_name("access-token-env")
+                    .long("access-token-env")
+                    .takes_value(true)
+                    .default_value("GOOGLE_OAUTH_ACCESS_TOKEN")
+                    .help("The environment variable to set the access token in")
+            )
+            .arg(
+                Arg::with_name("id-token-env")
+                    .long("id-token-env")
+                    .takes_value(true)
+                    .default_value("ID_TOKEN")
+                    .help("The environment variable to set the id token in")
+            )
+            .arg(min_valid_arg.clone())
+            .arg(
+                Arg::with_name("command")
+                    .value_name("COMMAND")
+                    .required(true)
+                    .multiple(true)
+                    .last(true)
+                    .help("The command to run, and its arguments, after --")
             )
         )
         .subcommand(SubCommand::with_name("accesstoken")
             .arg(config_name_arg.clone())
+            .arg(min_valid_arg.clone())
+            .arg(
+                Arg::with_name("subject")
+                    .long("subject")
+                    .takes_value(true)
+                    .value_name("USER")
+                    .help("Get an access token of a service account that acts as this user, instead of the subject of the profile")
+                    .long_help("Get an access token of a service account that acts as this user, instead of the subject of the profile. Needs domain-wide delegation. The tokens of each user are cached separately."),
+            )
         )
         .subcommand(SubCommand::with_name("idtoken")
             .arg(config_name_arg.clone())
+            .arg(min_valid_arg.clone())
         );
 
-    let config_base_path = match ConfigBasePath::default() {
-        Ok(googauth_config_base_path) => googauth_config_base_path,
+    let matches = app.get_matches();
+
+    let output = OutputFormat::from_name(
+        matches
+            .subcommand()
+            .1
+            .and_then(|matches| matches.value_of("output"))
+            .or_else(|| matches.value_of("output"))
+            .unwrap_or("text"),
+    );
+
+    let passphrase = Arc::new(Mutex::new(None));
+
+    let config_dir = matches
+        .subcommand()
+        .1
+        .and_then(|matches| matches.value_of("config-dir"))
+        .or_else(|| matches.value_of("config-dir"));
+
+    let config_base_path = match ConfigBasePath::resolve(config_dir.map(PathBuf::from), &ConfigEnvironment::current()) {
+        Ok(googauth_config_base_path) => {
+            googauth_config_base_path.with_passphrase_provider(passphrase_provider(passphrase.clone()))
+        }
         Err(err) => {
-            println!("No base path found: {:?}", err);
-            exit(0);
+            print_lib_error_and_exit(output, &err);
+            unreachable!()
         }
     };
 
-    let matches = app.get_matches();
-
     match matches.subcommand() {
         ("list", Some(_)) => {
-            let config_list = match ConfigFile::list_configs(&config_base_path) {
+            ensure_not_env(output, "list");
+            let config_list = match ConfigFile::scan_configs(&config_base_path) {
                 Ok(config_list) => config_list,
                 Err(err) => {
-                    eprintln!("Error: {:?}", err);
-                    print_success_and_exit("No configs available");
+                    print_lib_error_and_exit(output, &err);
                     unreachable!()
                 }
             };
-            for config_file in config_list {
+            warn_about_unreadable_configs(&config_list);
+            if output == OutputFormat::Json {
+                let mut profiles: Vec<Value> = config_list
+                    .configs
+                    .iter()
+                    .map(|config_file| {
+                        json!({
+                            "profile": config_file.name,
+                            "scopes": config_file.scopes,
+                            "issuer_url": config_file.issuer_url,
+                            "locked": false,
+                        })
+                    })
+                    .collect();
+                profiles.extend(config_list.locked.iter().map(|name| {
+                    json!({
+                        "profile": name,
+                        "locked": true,
+                    })
+                }));
+                print_json(&Value::Array(profiles));
+                return;
+            }
+            if config_list.configs.is_empty() && config_list.locked.is_empty() {
+                print_success_and_exit("No configs available");
+            }
+            for config_file in config_list.configs {
                 println!(
                     "{0: <25} | {1: <115}",
                     config_file.name,
                     config_file.scopes.join(", ")
                 );
             }
+            for name in config_list.locked {
+                println!("{0: <25} | {1: <115}", name, "(locked, the passphrase is wrong or missing)");
+            }
+        }
+        ("status", Some(matches)) => {
+            ensure_not_env(output, "status");
+            let (config_list, locked) = match matches.value_of("config") {
+                Some(config_name) => match ConfigFile::read_config(&profile_name_of(output, config_name), &config_base_path) {
+                    Ok(config) => (vec![config], Vec::new()),
+                    Err(err) => {
+                        print_lib_error_and_exit(output, &err);
+                        unreachable!()
+                    }
+                },
+                None => match ConfigFile::scan_configs(&config_base_path) {
+                    Ok(config_list) => {
+                        warn_about_unreadable_configs(&config_list);
+                        (config_list.configs, config_list.locked)
+                    }
+                    Err(err) => {
+                        print_lib_error_and_exit(output, &err);
+                        unreachable!()
+                    }
+                },
+            };
+            let warn_within = match parse_duration(matches.value_of("warn-within").unwrap()) {
+                Ok(duration) => duration.as_secs(),
+                Err(e) => {
+                    print_error_and_exit(output, "invalid_duration", &e);
+                    unreachable!()
+                }
+            };
+            let now = unix_now();
+
+            let mut flagged = false;
+            let mut profiles = Vec::new();
+            for config in &config_list {
+                let access_token_state = TokenState::of(config.access_token.as_ref(), now, warn_within);
+                let id_token_state = TokenState::of(config.id_token.as_ref(), now, warn_within);
+                // Expired tokens are refreshed when they are used, unless there is nothing to refresh them with.
+                let can_refresh = config.refresh_token.is_some() || config.service_account_key.is_some();
+                flagged |= !can_refresh && (access_token_state.is_flagged() || id_token_state.is_flagged());
+
+                if output == OutputFormat::Json {
+                    profiles.push(json!({
+                        "profile": config.name,
+                        "locked": false,
+                        "refresh_token": config.refresh_token.is_some(),
+                        "access_token": access_token_state.to_json(config.access_token.as_ref(), now),
+                        "id_token": id_token_state.to_json(config.id_token.as_ref(), now),
+                    }));
+                    continue;
+                }
+
+                println!("{}", config.name);
+
+                if config.refresh_token.is_some() {
+                    println!("  {0: <15} present", "Refresh token:");
+                } else {
+                    println!("  {0: <15} MISSING, login is required when the tokens expire", "Refresh token:");
+                }
+
+                for (label, token, state) in [
+                    ("Access token:", &config.access_token, access_token_state),
+                    ("ID token:", &config.id_token, id_token_state),
+                ] {
+                    let status = match (token, state) {
+                        (Some(token), TokenState::Expired) => format!(
+                            "EXPIRED {} ago ({})",
+                            format_duration(now - token.exp),
+                            format_timestamp(token.exp)
+                        ),
+                        (Some(token), TokenState::Expiring) => format!(
+                            "EXPIRING in {} ({})",
+                            format_duration(token.exp - now),
+                            format_timestamp(token.exp)
+                        ),
+                        (Some(_), TokenState::NoExpiry) => String::from("valid, expiry unknown"),
+                        (Some(token), TokenState::Valid) => format!(
+                            "valid for {} ({})",
+                            format_duration(token.exp - now),
+                            format_timestamp(token.exp)
+                        ),
+                        _ => String::from("MISSING"),
+                    };
+                    println!("  {0: <15} {1}", label, status);
+                }
+            }
+
+            // The tokens of profiles that can not be decrypted can not be checked.
+            flagged |= !locked.is_empty();
+            for name in locked {
+                if output == OutputFormat::Json {
+                    profiles.push(json!({
+                        "profile": name,
+                        "locked": true,
+                    }));
+                    continue;
+                }
+
+                println!("{}", name);
+                println!("  LOCKED, the passphrase is wrong or missing");
+            }
+
+            if output == OutputFormat::Json {
+                print_json(&Value::Array(profiles));
+            }
+
+            if flagged && matches.is_present("check") {
+                exit(1);
+            }
         }
         ("login", Some(matches)) => {
-            let config_name = match matches.value_of("config") {
-                Some(config_name) => config_name,
+            ensure_not_env(output, "login");
+            let config_name = &match matches.value_of("config") {
+                Some(config_name) => profile_name_of(output, config_name),
                 None => {
-                    print_error_and_exit("You must specify an configuration name");
+                    print_error_and_exit(output, "invalid_arguments", "You must specify an configuration name");
                     unreachable!()
                 }
             };
-            let mut config = match ConfigFile::read_config(&config_name, &config_base_path)
-            {
-                Err(_err) => {
-                    // TODO: Check err?
-                    let client_id = match matches.value_of("clientid") {
-                        Some(client_id) => client_id,
-                        None => {
-                            print_error_and_exit(&format!(
-                                "You must specify a client id for the configuration {}",
-                                &config_name
-                            ));
-                            unreachable!()
-                        }
-                    };
-                    let client_secret = match matches.value_of("secret") {
-                        Some(client_secret) => client_secret,
-                        None => {
-                            print_error_and_exit(&format!(
-                                "You must specify a client secret for the configuration {}",
-                                &config_name
-                            ));
-                            unreachable!()
-                        }
-                    };
+            let user_credentials_given = ["client-secrets-file", "clientid", "secret"]
+                .iter()
+                .any(|name| matches.is_present(name));
+            if user_credentials_given && matches.is_present("service-account-key") {
+                print_error_and_exit(
+                    output,
+                    "invalid_arguments",
+                    "--service-account-key can not be combined with --client-secrets-file, --id or --secret",
+                );
+            }
+            let mut config = match ConfigFile::read_config(config_name, &config_base_path) {
+                Err(LibError::NoSuchConfiguration(_)) => {
                     let scopes = match matches.values_of_lossy("scopes") {
                         Some(scopes) => scopes,
+                        None if matches.is_present("service-account-key") => Vec::new(),
                         None => {
-                            print_error_and_exit(&format!(
+                            print_error_and_exit(output, "invalid_arguments", &format!(
                                 "You must specify at least one scope for the configuration {}",
                                 &config_name
                             ));
                             unreachable!()
                         }
                     };
-                    let redirect_url = match matches.value_of("redirect") {
-                        Some(redirect_url) => redirect_url,
-                        None => {
-                            print_error_and_exit(&format!(
-                                "You must specify a redirect URL for the configuration {}",
-                                &config_name
-                            ));
-                            unreachable!()
+                    let mut new_config = match (
+                        matches.value_of("service-account-key"),
+                        matches.value_of("client-secrets-file"),
+                    ) {
+                        (Some(path), _) => read_service_account_key(output, config_name, path, &scopes),
+                        (None, Some(path)) => {
+                            let mut new_config = read_client_secrets(output, config_name, path, &scopes);
+                            if let Some(redirect_url) = explicit_redirect_url_of(matches) {
+                                new_config.redirect_url = redirect_url.to_string();
+                            }
+                            new_config
                         }
+                        (None, None) => new_config_from_args(output, config_name, matches, &scopes),
                     };
-                    let new_config = ConfigFile::new(
-                        &config_name,
-                        client_id,
-                        client_secret,
-                        &scopes,
-                        redirect_url,
-                    );
+                    new_config.target_audience = matches.value_of("target-audience").map(str::to_string);
+                    set_service_account_options_of(output, matches, &mut new_config);
+                    if let Some(issuer_url) = matches.value_of("issuer") {
+                        new_config.issuer_url = issuer_url.to_string();
+                    }
+                    if let Some(min_validity) = min_validity_of(matches) {
+                        new_config.min_validity = Some(min_validity.as_secs());
+                    }
+                    if matches.is_present("encrypt") {
+                        ask_for_new_passphrase(output, config_name, &passphrase);
+                        new_config.encrypted = true;
+                    }
 
                     match new_config.save_config(&config_base_path) {
                         Ok(_) => (),
                         Err(e) => {
-                            print_error_and_exit(&e.to_string());
+                            print_lib_error_and_exit(output, &e);
                             unreachable!()
                         }
                     }
 
-                    if let Ok(config) =
-                        ConfigFile::config_file(&config_name, &config_base_path)
+                    if output == OutputFormat::Text
+                        && let Some(config_str) = ConfigFile::config_file(config_name, &config_base_path).to_str()
                     {
-                        if let Some(config_str) = config.to_str() {
-                            println!("Saved configuration to {}", config_str)
-                        }
+                        println!("Saved configuration to {}", config_str)
                     }
 
                     new_config
                 }
                 Ok(mut config) => {
-                    if let Some(client_id) = matches.value_of("clientid") {
-                        config.client_id = client_id.to_string();
-                    }
-                    if let Some(client_secret) = matches.value_of("secret") {
-                        config.client_secret = client_secret.to_string();
-                    }
+                    ensure_no_settings_of_config_toml_profile(output, matches, config_name, &config_base_path);
                     if let Some(scopes) = matches.values_of_lossy("scopes") {
                         config.scopes = scopes;
                     }
-                    if let Some(redirect_url) = matches.value_of("redirect") {
+                    if user_credentials_given {
+                        // The profile signs in as a user from now on.
+                        config.service_account_key = None;
+                        config.target_audience = None;
+                        config.self_signed_jwt = false;
+                        config.jwt_audience = None;
+                        config.subject = None;
+                    }
+                    if let Some(path) = matches.value_of("service-account-key") {
+                        let imported = read_service_account_key(output, config_name, path, &config.scopes);
+                        config.client_id = imported.client_id;
+                        config.service_account_key = imported.service_account_key;
+                    } else if let Some(path) = matches.value_of("client-secrets-file") {
+                        let imported = read_client_secrets(output, config_name, path, &config.scopes);
+                        config.client_id = imported.client_id;
+                        config.client_secret = imported.client_secret;
+                        config.redirect_url = imported.redirect_url;
+                    } else {
+                        if let Some(client_id) = matches.value_of("clientid") {
+                            config.client_id = client_id.to_string();
+                        }
+                        if let Some(client_secret) = matches.value_of("secret") {
+                            config.client_secret = client_secret.to_string();
+                        }
+                    }
+                    if let Some(redirect_url) = explicit_redirect_url_of(matches) {
                         config.redirect_url = redirect_url.to_string();
                     };
+                    if let Some(target_audience) = matches.value_of("target-audience") {
+                        config.target_audience = Some(target_audience.to_string());
+                    }
+                    set_service_account_options_of(output, matches, &mut config);
+                    if let Some(issuer_url) = matches.value_of("issuer") {
+                        config.issuer_url = issuer_url.to_string();
+                    }
+                    if let Some(min_validity) = min_validity_of(matches) {
+                        config.min_validity = Some(min_validity.as_secs());
+                    }
 
                     config
                 }
+                Err(err) => {
+                    print_lib_error_and_exit(output, &err);
+                    unreachable!()
+                }
+            };
+
+            if matches.is_present("encrypt") && !config.encrypted {
+                ask_for_new_passphrase(output, &config.name, &passphrase);
+                config.encrypted = true;
+            }
+
+            if matches.is_present("random-port")
+                && let Err(e) = config.set_random_redirect_port()
+            {
+                print_lib_error_and_exit(output, &e);
+                unreachable!()
+            }
+
+            // The tokens of other subjects may have been issued with other settings or another key.
+            config.subject_access_tokens.clear();
+
+            let login_result = if config.service_account_key.is_some() {
+                service_account_login(&mut config, &config_base_path).await
+            } else if matches.is_present("device") {
+                google_device_login(&mut config, &config_base_path).await
+            } else if matches.is_present("manual") {
+                google_manual_login(&mut config, &config_base_path).await
+            } else {
+                let timeout = matches
+                    .value_of("timeout")
+                    .and_then(|timeout| timeout.parse().ok())
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_REDIRECT_TIMEOUT);
+                google_login_with_timeout(&mut config, &config_base_path, timeout).await
             };
 
-            match google_login(&mut config, &config_base_path).await {
+            match login_result {
                 Ok(_) => (),
                 Err(e) => {
-                    print_error_and_exit(&e.to_string());
+                    print_lib_error_and_exit(output, &e);
                     unreachable!()
                 }
             }
 
-            println!(
-                "Successfully logged in and created the configuration profile {}",
-                &config.name
+            print_result(
+                output,
+                &config.name,
+                &format!(
+                    "Successfully logged in and created the configuration profile {}",
+                    &config.name
+                ),
             );
         }
-        ("accesstoken", Some(matches)) => {
-            let config_name = matches.value_of("config").unwrap().to_string();
-            let access_token =
-                match get_access_token_from_config(&config_name, &config_base_path).await {
-                    Ok(access_token) => access_token,
+        ("encrypt", Some(matches)) => {
+            ensure_not_env(output, "encrypt");
+            let config_name = profile_name_of(output, matches.value_of("config").unwrap());
+
+            let mut config = match ConfigFile::read_config(&config_name, &config_base_path) {
+                Ok(config) => config,
+                Err(err) => {
+                    print_lib_error_and_exit(output, &err);
+                    unreachable!()
+                }
+            };
+
+            if config.encrypted {
+                print_result(
+                    output,
+                    &config_name,
+                    &format!("The configuration profile {} is already encrypted", &config_name),
+                );
+                return;
+            }
+
+            ask_for_new_passphrase(output, &config_name, &passphrase);
+            config.encrypted = true;
+
+            if let Err(e) = config.save_config(&config_base_path) {
+                print_lib_error_and_exit(output, &e);
+                unreachable!()
+            }
+
+            print_result(
+                output,
+                &config_name,
+                &format!("Encrypted the configuration profile {}", &config_name),
+            );
+        }
+        ("logout", Some(matches)) => {
+            ensure_not_env(output, "logout");
+            let config_name = profile_name_of(output, matches.value_of("config").unwrap());
+
+            let mut config = match ConfigFile::read_config(&config_name, &config_base_path) {
+                Ok(config) => config,
+                Err(err) => {
+                    print_lib_error_and_exit(output, &err);
+                    unreachable!()
+                }
+            };
+
+            if let Err(e) = revoke_google_login(&mut config, &config_base_path).await {
+                print_lib_error_and_exit(output, &e);
+                unreachable!()
+            }
+
+            if matches.is_present("delete") {
+                let message = match ConfigFile::delete_config(&config_name, &config_base_path) {
+                    Ok(DeletedConfig::Profile) => {
+                        format!("Logged out and deleted the configuration profile {}", &config_name)
+                    }
+                    Ok(DeletedConfig::Tokens) => format!(
+                        "Logged out and removed the cached tokens of {}; the profile is defined in {}",
+                        &config_name, CONFIG_TOML
+                    ),
                     Err(e) => {
-                        print_error_and_exit(&e.to_string());
-                        unreachable!();
+                        print_lib_error_and_exit(output, &e);
+                        unreachable!()
                     }
                 };
-            println!("{}", access_token.secret);
+                print_result(output, &config_name, &message);
+            } else {
+                print_result(
+                    output,
+                    &config_name,
+                    &format!("Logged out of the configuration profile {}", &config_name),
+                );
+            }
+        }
+        ("show", Some(matches)) => {
+            ensure_not_env(output, "show");
+            let config_name = profile_name_of(output, matches.value_of("config").unwrap());
+
+            let config = match ConfigFile::read_config(&config_name, &config_base_path) {
+                Ok(config) => config,
+                Err(err) => {
+                    print_lib_error_and_exit(output, &err);
+                    unreachable!()
+                }
+            };
+
+            if output == OutputFormat::Json {
+                print_json(&json!({
+                    "profile": config.name,
+                    "issuer_url": config.issuer_url,
+                    "client_id": config.client_id,
+                    "scopes": config.scopes,
+                    "redirect_url": config.redirect_url,
+                    "encrypted": config.encrypted,
+                    "min_validity": config.default_min_validity().as_secs(),
+                    "service_account_key": config.service_account_key,
+                    "target_audience": config.target_audience,
+                    "self_signed_jwt": config.self_signed_jwt,
+                    "jwt_audience": config.jwt_audience,
+                    "subject": config.subject,
+                    "refresh_token": config.refresh_token.is_some(),
+                    "access_token_expiry": config.access_token.as_ref().map(|token| token.exp),
+                    "id_token_expiry": config.id_token.as_ref().map(|token| token.exp),
+                }));
+                return;
+            }
+
+            println!("{0: <15} {1}", "Name:", config.name);
+            println!("{0: <15} {1}", "Issuer:", config.issuer_url);
+            println!("{0: <15} {1}", "Client id:", config.client_id);
+            match &config.service_account_key {
+                Some(service_account_key) => {
+                    println!("{0: <15} {1}", "Key file:", service_account_key);
+                    println!("{0: <15} {1}", "Scopes:", config.scopes.join(", "));
+                    println!("{0: <15} {1}", "Audience:", config.target_audience.as_deref().unwrap_or("<none>"));
+                    if let Some(subject) = &config.subject {
+                        println!("{0: <15} {1}", "Subject:", subject);
+                    }
+                    if config.self_signed_jwt {
+                        let jwt_audience = config.jwt_audience.as_deref().unwrap_or("<scopes>");
+                        println!("{0: <15} {1}", "Self-signed:", jwt_audience);
+                    }
+                }
+                None => {
+                    println!("{0: <15} {1}", "Client secret:", redact(Some(&config.client_secret)));
+                    println!("{0: <15} {1}", "Scopes:", config.scopes.join(", "));
+                    println!("{0: <15} {1}", "Redirect URL:", config.redirect_url);
+                }
+            }
+            println!("{0: <15} {1}", "Encrypted:", if config.encrypted { "yes" } else { "no" });
+            println!(
+                "{0: <15} {1}",
+                "Min validity:",
+                format_duration(config.default_min_validity().as_secs())
+            );
+            println!("{0: <15} {1}", "Refresh token:", redact(config.refresh_token.as_ref()));
+            println!("{0: <15} {1}", "Access token:", describe_token(config.access_token.as_ref()));
+            println!("{0: <15} {1}", "ID token:", describe_token(config.id_token.as_ref()));
+        }
+        ("delete", Some(matches)) => {
+            ensure_not_env(output, "delete");
+            let config_name = profile_name_of(output, matches.value_of("config").unwrap());
+
+            let message = match ConfigFile::delete_config(&config_name, &config_base_path) {
+                Ok(DeletedConfig::Profile) => format!("Deleted the configuration profile {}", &config_name),
+                Ok(DeletedConfig::Tokens) => format!(
+                    "Removed the cached tokens of {}; the profile is defined in {}",
+                    &config_name, CONFIG_TOML
+                ),
+                Err(e) => {
+                    print_lib_error_and_exit(output, &e);
+                    unreachable!()
+                }
+            };
+
+            print_result(output, &config_name, &message);
+        }
+        ("rename", Some(matches)) => {
+            ensure_not_env(output, "rename");
+            let config_name = profile_name_of(output, matches.value_of("config").unwrap());
+            let new_config_name = profile_name_of(output, matches.value_of("new-config").unwrap());
+
+            if let Err(e) =
+                ConfigFile::rename_config(&config_name, &new_config_name, &config_base_path)
+            {
+                print_lib_error_and_exit(output, &e);
+                unreachable!()
+            }
+
+            print_result(
+                output,
+                &new_config_name,
+                &format!(
+                    "Renamed the configuration profile {} to {}",
+                    &config_name, &new_config_name
+                ),
+            );
+        }
+        ("copy", Some(matches)) => {
+            ensure_not_env(output, "copy");
+            let config_name = profile_name_of(output, matches.value_of("config").unwrap());
+            let new_config_name = profile_name_of(output, matches.value_of("new-config").unwrap());
+
+            if let Err(e) =
+                ConfigFile::copy_config(&config_name, &new_config_name, &config_base_path)
+            {
+                print_lib_error_and_exit(output, &e);
+                unreachable!()
+            }
+
+            print_result(
+                output,
+                &new_config_name,
+                &format!(
+                    "Copied the configuration profile {} to {}. Use login to sign in to it.",
+                    &config_name, &new_config_name
+                ),
+            );
+        }
+        ("export-adc", Some(matches)) => {
+            ensure_not_env(output, "export-adc");
+            let config_name = &profile_name_of(output, matches.value_of("config").unwrap());
+            let path = adc_path_of(output, matches);
+
+            let result = ConfigFile::read_config(config_name, &config_base_path)
+                .and_then(|config| config.export_adc(&path));
+            if let Err(e) = result {
+                print_lib_error_and_exit(output, &e);
+                unreachable!()
+            }
+
+            print_result(
+                output,
+                config_name,
+                &format!(
+                    "Saved the configuration profile {} as Application Default Credentials to {}",
+                    config_name,
+                    path.display()
+                ),
+            );
+        }
+        ("import-adc", Some(matches)) => {
+            ensure_not_env(output, "import-adc");
+            let config_name = &profile_name_of(output, matches.value_of("config").unwrap());
+            let path = adc_path_of(output, matches);
+            let scopes = matches
+                .values_of_lossy("scopes")
+                .unwrap_or_else(|| ADC_SCOPES.iter().map(|scope| scope.to_string()).collect());
+
+            if let Err(e) = ConfigFile::import_adc(config_name, &path, &scopes, &config_base_path).await {
+                print_lib_error_and_exit(output, &e);
+                unreachable!()
+            }
+
+            print_result(
+                output,
+                config_name,
+                &format!(
+                    "Created the configuration profile {} from the Application Default Credentials in {}",
+                    config_name,
+                    path.display()
+                ),
+            );
+        }
+        ("exec", Some(matches)) => {
+            ensure_not_env(output, "exec");
+            let config_name = profile_name_of(output, matches.value_of("config").unwrap());
+
+            let mut config = match ConfigFile::read_config(&config_name, &config_base_path) {
+                Ok(config) => config,
+                Err(err) => {
+                    print_lib_error_and_exit(output, &err);
+                    unreachable!()
+                }
+            };
+
+            let min_validity =
+                min_validity_of(matches).unwrap_or_else(|| config.default_min_validity());
+            if let Err(err) = check_tokens_with_min_validity(&mut config, &config_base_path, min_validity).await {
+                print_lib_error_and_exit(output, &err);
+                unreachable!()
+            }
+            let access_token = match &config.access_token {
+                Some(access_token) => access_token,
+                None => {
+                    print_lib_error_and_exit(output, &LibError::CouldNotReadConfigCorrupt(config.name.clone()));
+                    unreachable!()
+                }
+            };
+
+            let mut command_line = matches.values_of("command").unwrap();
+            let mut command = Command::new(command_line.next().unwrap());
+            command.args(command_line);
+            command.env(matches.value_of("access-token-env").unwrap(), &access_token.secret);
+            if let Some(id_token) = &config.id_token {
+                command.env(matches.value_of("id-token-env").unwrap(), &id_token.secret);
+            }
+
+            // exec only returns if the command could not be started.
+            let err = command.exec();
+            print_error_and_exit(
+                output,
+                "exec_failed",
+                &format!("Could not run {}: {}", matches.value_of("command").unwrap(), err),
+            );
+        }
+        ("accesstoken", Some(matches)) => {
+            let config_name = profile_name_of(output, matches.value_of("config").unwrap());
+
+            let mut config = match ConfigFile::read_config(&config_name, &config_base_path) {
+                Ok(config) => config,
+                Err(err) => {
+                    print_lib_error_and_exit(output, &err);
+                    unreachable!()
+                }
+            };
+
+            let min_validity =
+                min_validity_of(matches).unwrap_or_else(|| config.default_min_validity());
+            if let Some(subject) = matches.value_of("subject") {
+                match get_access_token_from_config_as_subject(&config_name, &config_base_path, subject, Some(min_validity))
+                    .await
+                {
+                    Ok(access_token) => print_token(output, &config, &access_token, "ACCESS_TOKEN"),
+                    Err(err) => {
+                        print_lib_error_and_exit(output, &err);
+                        unreachable!()
+                    }
+                }
+                return;
+            }
+
+            if let Err(err) = check_token_with_min_validity(
+                config.access_token.clone(),
+                &mut config,
+                &config_base_path,
+                min_validity,
+            )
+            .await
+            {
+                print_lib_error_and_exit(output, &err);
+                unreachable!()
+            }
+
+            match &config.access_token {
+                Some(access_token) => print_token(output, &config, access_token, "ACCESS_TOKEN"),
+                None => {
+                    print_lib_error_and_exit(output, &LibError::CouldNotReadConfigCorrupt(config.name.clone()));
+                    unreachable!()
+                }
+            };
         }
         ("idtoken", Some(matches)) => {
-            let config_name = matches.value_of("config").unwrap().to_string();
+            let config_name = profile_name_of(output, matches.value_of("config").unwrap());
 
             let mut config = match ConfigFile::read_config(&config_name, &config_base_path)
             {
                 Ok(config) => config,
                 Err(err) => {
-                    eprintln!("Error when reading configuration: {:?}", err);
-                    exit(1);
+                    print_lib_error_and_exit(output, &err);
+                    unreachable!()
                 }
             };
 
-            if let Err(err) = check_token(config.id_token.clone(), &mut config, &config_base_path).await {
-                eprintln!("Error when checking the token: {:?}", err);
-                exit(1);
+            if config.service_account_key.is_some() && config.target_audience.is_none() {
+                print_lib_error_and_exit(output, &LibError::NoTargetAudience(config.name.clone()));
+                unreachable!()
+            }
+
+            let min_validity =
+                min_validity_of(matches).unwrap_or_else(|| config.default_min_validity());
+            if let Err(err) = check_token_with_min_validity(
+                config.id_token.clone(),
+                &mut config,
+                &config_base_path,
+                min_validity,
+            )
+            .await
+            {
+                print_lib_error_and_exit(output, &err);
+                unreachable!()
             }
 
             match &config.id_token {
-                Some(id_token) => println!("{}", id_token.secret),
+                Some(id_token) => print_token(output, &config, id_token, "ID_TOKEN"),
                 None => {
-                    print_error_and_exit(&format!(
+                    print_error_and_exit(output, "could_not_read_config_corrupt", &format!(
                         "Could not read id token from {}. Is the configuration corrupt?",
                         &config.name
                     ));
@@ -255,17 +1083,495 @@ async fn main() {
             };
         }
         _ => {
-            println!("You must select a sub command. See --help");
+            print_error_and_exit(output, "invalid_arguments", "You must select a sub command. See --help");
+        }
+    }
+}
+
+/// Print the result of a command that changes a profile.
+fn print_result(output: OutputFormat, config_name: &str, message: &str) {
+    match output {
+        OutputFormat::Json => print_json(&json!({ "profile": config_name, "message": message })),
+        OutputFormat::Text | OutputFormat::Env => println!("{}", message),
+    }
+}
+
+/// Print a token, e.g. `GOOGAUTH_ACCESS_TOKEN` in the env format.
+fn print_token(output: OutputFormat, config: &ConfigFile, token: &Token, name: &str) {
+    match output {
+        OutputFormat::Text => println!("{}", token.secret),
+        OutputFormat::Json => print_json(&json!({
+            "profile": config.name,
+            "token": token.secret,
+            "expiry": token.exp,
+            "scopes": config.scopes,
+        })),
+        OutputFormat::Env => print_env(&[
+            ("GOOGAUTH_PROFILE", config.name.clone()),
+            (&format!("GOOGAUTH_{}", name), token.secret.clone()),
+            (&format!("GOOGAUTH_{}_EXPIRY", name), token.exp.to_string()),
+            ("GOOGAUTH_SCOPES", config.scopes.join(" ")),
+        ]),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum TokenState {
+    Missing,
+    NoExpiry,
+    Expired,
+    Expiring,
+    Valid,
+}
+
+impl TokenState {
+    fn of(token: Option<&Token>, now: u64, warn_within: u64) -> TokenState {
+        match token {
+            None => TokenState::Missing,
+            Some(token) if !token.has_expiry() => TokenState::NoExpiry,
+            Some(token) if token.exp < now => TokenState::Expired,
+            Some(token) if token.exp - now < warn_within => TokenState::Expiring,
+            Some(_) => TokenState::Valid,
+        }
+    }
+
+    fn is_flagged(&self) -> bool {
+        !matches!(self, TokenState::Valid | TokenState::NoExpiry)
+    }
+
+    fn to_json(self, token: Option<&Token>, now: u64) -> Value {
+        let state = match self {
+            TokenState::Missing => "missing",
+            TokenState::NoExpiry => "no_expiry",
+            TokenState::Expired => "expired",
+            TokenState::Expiring => "expiring",
+            TokenState::Valid => "valid",
+        };
+        json!({
+            "state": state,
+            "expiry": token.filter(|token| token.has_expiry()).map(|token| token.exp),
+            "expires_in": token
+                .filter(|token| token.has_expiry())
+                .map(|token| token.exp as i64 - now as i64),
+        })
+    }
+}
+
+fn redact<S: AsRef<str>>(secret: Option<S>) -> &'static str {
+    match secret {
+        Some(secret) if !secret.as_ref().is_empty() => "<redacted>",
+        _ => "<none>",
+    }
+}
+
+fn describe_token(token: Option<&Token>) -> String {
+    let token = match token {
+        Some(token) => token,
+        None => return String::from("<none>"),
+    };
+
+    if !token.has_expiry() {
+        return String::from("<redacted>, expiry unknown");
+    }
+
+    let now = unix_now();
+
+    if token.exp < now {
+        format!(
+            "<redacted>, expired {} ago ({})",
+            format_duration(now - token.exp),
+            format_timestamp(token.exp)
+        )
+    } else {
+        format!(
+            "<redacted>, expires in {} ({})",
+            format_duration(token.exp - now),
+            format_timestamp(token.exp)
+        )
+    }
+}
+
+/// A new configuration from the --id, --secret and --redirect arguments of the login command.
+fn new_config_from_args(output: OutputFormat, config_name: &str, matches: &ArgMatches, scopes: &[String]) -> ConfigFile {
+    let client_id = match matches.value_of("clientid") {
+        Some(client_id) => client_id,
+        None => {
+            print_error_and_exit(output, "invalid_arguments", &format!(
+                "You must specify a client id for the configuration {}",
+                &config_name
+            ));
+            unreachable!()
+        }
+    };
+    let client_secret = match matches.value_of("secret") {
+        Some(client_secret) => client_secret,
+        None => {
+            print_error_and_exit(output, "invalid_arguments", &format!(
+                "You must specify a client secret for the configuration {}",
+                &config_name
+            ));
+            unreachable!()
+        }
+    };
+    let redirect_url = match matches.value_of("redirect") {
+        Some(redirect_url) => redirect_url,
+        None => {
+            print_error_and_exit(output, "invalid_arguments", &format!(
+                "You must specify a redirect URL for the configuration {}",
+                &config_name
+            ));
+            unreachable!()
+        }
+    };
+    ConfigFile::new(config_name, client_id, client_secret, scopes, redirect_url)
+}
+
+/// A configuration with the client id, secret and redirect URL of a client_secret.json file.
+fn read_client_secrets(output: OutputFormat, config_name: &str, path: &str, scopes: &[String]) -> ConfigFile {
+    let json = match fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(e) => {
+            print_error_and_exit(
+                output,
+                "invalid_arguments",
+                &format!("Could not read the client secrets file {}: {}", path, e),
+            );
+            unreachable!()
+        }
+    };
+    match ConfigFile::from_client_secrets_json(config_name, &json, scopes) {
+        Ok(config) => config,
+        Err(e) => {
+            print_lib_error_and_exit(output, &e);
+            unreachable!()
         }
     }
 }
 
-fn print_error_and_exit(error_str: &str) {
-    eprintln!("Error: {}", error_str);
-    exit(1);
+/// The Application Default Credentials file given to the export-adc and import-adc commands.
+fn adc_path_of(output: OutputFormat, matches: &ArgMatches) -> PathBuf {
+    match matches.value_of("path") {
+        Some(path) => PathBuf::from(path),
+        None => match default_adc_path() {
+            Ok(path) => path,
+            Err(e) => {
+                print_lib_error_and_exit(output, &e);
+                unreachable!()
+            }
+        },
+    }
+}
+
+/// A configuration with the service account key file given to the login command.
+fn read_service_account_key(output: OutputFormat, config_name: &str, path: &str, scopes: &[String]) -> ConfigFile {
+    match ConfigFile::from_service_account_key_file(config_name, Path::new(path), scopes) {
+        Ok(config) => config,
+        Err(e) => {
+            print_lib_error_and_exit(output, &e);
+            unreachable!()
+        }
+    }
+}
+
+/// Set the options of the login command that only service account configurations have:
+/// self-signed access tokens, and the subject user.
+fn set_service_account_options_of(output: OutputFormat, matches: &ArgMatches, config: &mut ConfigFile) {
+    if !["self-signed-jwt", "jwt-audience", "subject"].iter().any(|name| matches.is_present(name)) {
+        return;
+    }
+    if config.service_account_key.is_none() {
+        print_lib_error_and_exit(output, &LibError::NotAServiceAccount(config.name.clone()));
+        unreachable!()
+    }
+    if matches.is_present("self-signed-jwt") || matches.is_present("jwt-audience") {
+        config.self_signed_jwt = true;
+    }
+    if let Some(jwt_audience) = matches.value_of("jwt-audience") {
+        config.jwt_audience = Some(jwt_audience.to_string());
+    }
+    if let Some(subject) = matches.value_of("subject") {
+        config.subject = Some(subject.to_string());
+    }
+}
+
+/// The redirect URL of the login command, only if it is given rather than the default value.
+fn explicit_redirect_url_of<'a>(matches: &'a ArgMatches) -> Option<&'a str> {
+    // Values from the environment do not count as occurrences.
+    if matches.occurrences_of("redirect") > 0 || env::var_os("REDIRECT").is_some() {
+        matches.value_of("redirect")
+    } else {
+        None
+    }
+}
+
+/// The settings of a profile that is only defined in config.toml are not saved, so exit
+/// instead of silently dropping the login arguments that would change them.
+fn ensure_no_settings_of_config_toml_profile(
+    output: OutputFormat,
+    matches: &ArgMatches,
+    name: &ProfileName,
+    config_base_path: &ConfigBasePath,
+) {
+    match ConfigFile::is_defined_in_config_toml(name, config_base_path) {
+        Ok(false) => return,
+        Ok(true) => (),
+        Err(e) => {
+            print_lib_error_and_exit(output, &e);
+            unreachable!()
+        }
+    }
+
+    let settings = [
+        ("clientid", "--id"),
+        ("secret", "--secret"),
+        ("client-secrets-file", "--client-secrets-file"),
+        ("scopes", "--scopes"),
+        ("random-port", "--random-port"),
+        ("issuer", "--issuer"),
+        ("min-valid", "--min-valid"),
+        ("service-account-key", "--service-account-key"),
+        ("target-audience", "--target-audience"),
+        ("self-signed-jwt", "--self-signed-jwt"),
+        ("jwt-audience", "--jwt-audience"),
+        ("subject", "--subject"),
+    ];
+    let mut given: Vec<&str> = settings
+        .iter()
+        .filter(|(arg, _)| matches.is_present(arg))
+        .map(|(_, flag)| *flag)
+        .collect();
+    if explicit_redirect_url_of(matches).is_some() {
+        given.push("--redirect");
+    }
+
+    if !given.is_empty() {
+        print_error_and_exit(output, "invalid_arguments", &format!(
+            "The profile {} is defined in {}, change it there instead of with {}",
+            name,
+            CONFIG_TOML,
+            given.join(", ")
+        ));
+    }
+}
+
+/// Report the files that could not be read as configurations on stderr, so that the output stays parseable.
+fn warn_about_unreadable_configs(config_list: &ConfigList) {
+    for (name, err) in &config_list.errors {
+        eprintln!("Warning: skipped {}: {}", name, err);
+    }
+}
+
+/// Parse the name of a profile, exiting if it can not be used as a profile name.
+fn profile_name_of(output: OutputFormat, name: &str) -> ProfileName {
+    match ProfileName::new(name) {
+        Ok(name) => name,
+        Err(e) => {
+            print_lib_error_and_exit(output, &e);
+            unreachable!()
+        }
+    }
+}
+
+fn min_validity_of(matches: &ArgMatches) -> Option<Duration> {
+    matches
+        .value_of("min-valid")
+        .and_then(|duration| parse_duration(duration).ok())
+}
+
+/// Parse a duration such as `300`, `30s`, `5m`, `1h` or `1d`. A number without a unit is in seconds.
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let (number, unit) = match duration.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => duration.split_at(index),
+        None => (duration, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(format!("Unknown time unit in {}, use s, m, h or d", duration)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("{} is not a duration, e.g. 30s, 5m or 1h", duration))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Format a number of seconds as e.g. `1d 2h`, `3h 4m` or `5m 6s`.
+fn format_duration(seconds: u64) -> String {
+    let (days, hours, minutes, seconds) = (
+        seconds / 86400,
+        seconds % 86400 / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+    );
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// Format a unix timestamp as a UTC date and time, e.g. `2021-03-04 05:06:07 UTC`.
+fn format_timestamp(timestamp: u64) -> String {
+    // Convert days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (timestamp / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        timestamp % 86400 / 3600,
+        timestamp % 3600 / 60,
+        timestamp % 60
+    )
+}
+
+/// Get the passphrase of encrypted profiles from the GOOGAUTH_PASSPHRASE environment
+/// variable, or by asking for it. The library remembers the passphrases that decrypt
+/// a profile, so that it is only asked for again for a profile that they do not decrypt.
+/// A new passphrase, see [ask_for_new_passphrase], is used for all profiles.
+fn passphrase_provider(new_passphrase: Arc<Mutex<Option<String>>>) -> PassphraseProvider {
+    Arc::new(move |config_name| {
+        if let Some(new_passphrase) = new_passphrase.lock().unwrap().as_ref() {
+            return Ok(new_passphrase.clone());
+        }
+
+        let passphrase = match env::var("GOOGAUTH_PASSPHRASE") {
+            Ok(passphrase) => passphrase,
+            Err(_) => rpassword::prompt_password(format!("Passphrase for {}: ", config_name))
+                .map_err(|_| LibError::PassphraseRequired(config_name.to_string()))?,
+        };
+        if passphrase.is_empty() {
+            return Err(LibError::PassphraseRequired(config_name.to_string()));
+        }
+        Ok(passphrase)
+    })
+}
+
+/// Ask for a new passphrase twice, unless it is set in the GOOGAUTH_PASSPHRASE environment variable.
+fn ask_for_new_passphrase(output: OutputFormat, config_name: &str, passphrase: &Mutex<Option<String>>) {
+    match env::var("GOOGAUTH_PASSPHRASE") {
+        Ok(new_passphrase) if new_passphrase.is_empty() => {
+            print_error_and_exit(output, "invalid_arguments", "The passphrase can not be empty");
+        }
+        Ok(_) => return,
+        Err(_) => (),
+    }
+
+    let new_passphrase = rpassword::prompt_password(format!("New passphrase for {}: ", config_name))
+        .and_then(|new_passphrase| {
+            rpassword::prompt_password("Repeat the passphrase: ")
+                .map(|repeated_passphrase| (new_passphrase, repeated_passphrase))
+        });
+
+    match new_passphrase {
+        Ok((new_passphrase, repeated_passphrase))
+            if new_passphrase == repeated_passphrase && !new_passphrase.is_empty() =>
+        {
+            *passphrase.lock().unwrap() = Some(new_passphrase);
+        }
+        Ok((new_passphrase, _)) if new_passphrase.is_empty() => {
+            print_error_and_exit(output, "invalid_arguments", "The passphrase can not be empty");
+        }
+        Ok(_) => {
+            print_error_and_exit(output, "invalid_arguments", "The passphrases do not match");
+        }
+        Err(e) => {
+            print_error_and_exit(output, "passphrase_required", &e.to_string());
+        }
+    }
 }
 
 fn print_success_and_exit(success_str: &str) {
     println!("{}", success_str);
     exit(0);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations_with_units() {
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(86400)));
+    }
+
+    #[test]
+    fn parses_durations_without_a_unit_as_seconds() {
+        assert_eq!(parse_duration("300"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("0"), Ok(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn rejects_unknown_units() {
+        for duration in ["5w", "5 m", "5ms", "-5m"] {
+            assert!(parse_duration(duration).is_err(), "{} should be rejected", duration);
+        }
+        assert_eq!(parse_duration("5w"), Err("Unknown time unit in 5w, use s, m, h or d".to_string()));
+    }
+
+    #[test]
+    fn rejects_durations_that_are_not_numbers() {
+        for duration in ["", "m", "five", "99999999999999999999"] {
+            assert!(parse_duration(duration).is_err(), "{} should be rejected", duration);
+        }
+    }
+
+    #[test]
+    fn rejects_durations_that_overflow() {
+        assert_eq!(
+            parse_duration("999999999999999d"),
+            Err("999999999999999d is not a duration, e.g. 30s, 5m or 1h".to_string())
+        );
+        assert_eq!(parse_duration("18446744073709551615"), Ok(Duration::from_secs(u64::MAX)));
+    }
+
+    #[test]
+    fn formats_the_epoch() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+    }
+
+    #[test]
+    fn formats_leap_days() {
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_timestamp(951_868_799), "2000-02-29 23:59:59 UTC");
+        assert_eq!(format_timestamp(1_709_164_800), "2024-02-29 00:00:00 UTC");
+        // 2100 is not a leap year.
+        assert_eq!(format_timestamp(4_107_542_400), "2100-03-01 00:00:00 UTC");
+    }
+
+    #[test]
+    fn formats_year_boundaries() {
+        assert_eq!(format_timestamp(946_684_799), "1999-12-31 23:59:59 UTC");
+        assert_eq!(format_timestamp(946_684_800), "2000-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(1_614_834_367), "2021-03-04 05:06:07 UTC");
+    }
+}
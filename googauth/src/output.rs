@@ -0,0 +1,67 @@
+use std::process::exit;
+
+use googauth_lib::LibError;
+use serde_json::{json, Value};
+
+/// The format that a command prints its result, and any error, in.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human readable text.
+    Text,
+    /// A JSON object, or an array of objects, on a single line.
+    Json,
+    /// `export NAME='value'` lines that can be evaluated by a shell.
+    Env,
+}
+
+impl OutputFormat {
+    pub const NAMES: [&'static str; 3] = ["text", "json", "env"];
+
+    pub fn from_name(name: &str) -> OutputFormat {
+        match name {
+            "json" => OutputFormat::Json,
+            "env" => OutputFormat::Env,
+            _ => OutputFormat::Text,
+        }
+    }
+}
+
+/// Print a JSON value on standard out.
+pub fn print_json(value: &Value) {
+    println!("{}", value);
+}
+
+/// Print shell variable assignments on standard out.
+pub fn print_env(variables: &[(&str, String)]) {
+    for (name, value) in variables {
+        println!("export {}='{}'", name, value.replace('\'', r"'\''"));
+    }
+}
+
+/// Print an error on standard error and exit with code 1.
+/// In the JSON format the error is printed as `{"error":{"code":...,"message":...}}`.
+pub fn print_error_and_exit(output: OutputFormat, code: &str, error_str: &str) {
+    match output {
+        OutputFormat::Json => eprintln!(
+            "{}",
+            json!({ "error": { "code": code, "message": error_str } })
+        ),
+        OutputFormat::Text | OutputFormat::Env => eprintln!("Error: {}", error_str),
+    }
+    exit(1);
+}
+
+pub fn print_lib_error_and_exit(output: OutputFormat, error: &LibError) {
+    print_error_and_exit(output, error.code(), &error.to_string());
+}
+
+/// Exit with an error if the command can not print its result in the env format.
+pub fn ensure_not_env(output: OutputFormat, command: &str) {
+    if output == OutputFormat::Env {
+        print_error_and_exit(
+            output,
+            "unsupported_output",
+            &format!("The {} command does not support the env output format", command),
+        );
+    }
+}